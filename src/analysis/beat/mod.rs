@@ -0,0 +1,390 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::audio::Frame;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{collections::VecDeque, f32::consts::PI, sync::Arc, time::Duration};
+
+/// samples per FFT window, and samples between the starts of two windows.
+const WINDOW: usize = 1024;
+const HOP: usize = 512;
+
+/// seconds of onset strength kept for the adaptive threshold and for tempo estimation.
+const THRESHOLD_SECONDS: f32 = 0.5;
+const TEMPO_SECONDS: f32 = 6.0;
+
+/// tempo range considered by the estimator, in beats per minute.
+const MIN_BPM: f32 = 70.0;
+const MAX_BPM: f32 = 180.0;
+
+/// A beat found in the stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Beat {
+    /// position of the beat, on the timeline of the frames it was found in.
+    pub time: Duration,
+    /// how sure the tracker is that this is a beat, between 0.0 and 1.0.
+    pub confidence: f32,
+    /// tempo estimate at the time of the beat, once enough of the song has been heard.
+    pub bpm: Option<f32>,
+}
+
+/// `BeatTracker` finds onsets in a stream of decoded frames using spectral flux,
+/// estimates the tempo from the autocorrelation of the onset strength,
+/// and reports the onsets that fall on the tempo's grid as beats.
+///
+/// Spectral flux is the sum of the increases in (log) magnitude of every frequency bin
+/// from one window to the next, which spikes whenever a new sound starts, such as a kick drum.
+///
+/// Beats are timed from where each frame says it starts rather than by counting the samples
+/// fed in, so a stream with a gap in it, such as one that was seeked, keeps its timing.
+pub struct BeatTracker {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    // mono samples that have not been through a full window yet
+    pending: Vec<f32>,
+    previous: Vec<f32>,
+    // onset strength of every hop, newest last
+    flux: VecDeque<f32>,
+    sample_rate: u32,
+    // where the stream was when the tracker last started over,
+    // and the number of samples from there to the first pending one
+    base: Duration,
+    offset: u64,
+    last_beat: Option<f32>,
+    tempo: Option<(f32, f32)>,
+}
+
+impl BeatTracker {
+    pub fn new() -> Self {
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(WINDOW),
+            window: (0..WINDOW)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / WINDOW as f32).cos())
+                .collect(),
+            pending: Vec::with_capacity(WINDOW * 2),
+            previous: vec![0.0; WINDOW / 2],
+            flux: VecDeque::new(),
+            sample_rate: 44100,
+            base: Duration::from_secs(0),
+            offset: 0,
+            last_beat: None,
+            tempo: None,
+        }
+    }
+
+    /// current tempo estimate in beats per minute.
+    pub fn bpm(&self) -> Option<f32> {
+        self.tempo.map(|(bpm, _)| bpm)
+    }
+
+    /// Feed the next decoded `frame`, which starts at `start`, to the tracker,
+    /// returning the beat it holds, if any.
+    /// A frame that does not follow on from the one before starts the analysis over from it.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use kartina::{analysis::beat::BeatTracker, audio::Frame, Preset, State};
+    /// # use std::time::Duration;
+    /// # fn main() -> kartina::Result<()> {
+    /// # let mut state = block_on(State::headless(800, 600, &Preset::default()))?;
+    /// # let frame = Frame { data: vec![0; 2304], sample_rate: 44100, channels: 2 };
+    /// let mut tracker = BeatTracker::new();
+    /// if let Some(beat) = tracker.process(&frame, Duration::from_secs(0)) {
+    ///     state.beat(&beat);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn process(&mut self, frame: &Frame, start: Duration) -> Option<Beat> {
+        let channels = frame.channels.max(1);
+        let expected = self.time(self.offset + self.pending.len() as u64);
+        let tolerance = Duration::from_millis(1);
+        if start > expected + tolerance || start + tolerance < expected {
+            // the onsets on either side of a gap have nothing to do with each other
+            self.base = start;
+            self.offset = 0;
+            self.pending.clear();
+            self.flux.clear();
+            self.last_beat = None;
+        }
+        if frame.sample_rate > 0 {
+            self.sample_rate = frame.sample_rate;
+        }
+        self.pending.extend(
+            frame
+                .data
+                .chunks(channels)
+                .map(|s| s.iter().map(|s| *s as f32).sum::<f32>() / channels as f32 / 32768.0),
+        );
+
+        let mut beat = None;
+        while self.pending.len() >= WINDOW {
+            self.analyze_hop();
+            self.pending.drain(..HOP);
+            self.offset += HOP as u64;
+            if let Some(found) = self.pick() {
+                beat = Some(found);
+            }
+        }
+        beat
+    }
+
+    /// time of the sample `offset` samples after the tracker last started over.
+    fn time(&self, offset: u64) -> Duration {
+        self.base + Duration::from_secs_f64(offset as f64 / self.sample_rate as f64)
+    }
+
+    /// duration of one hop in seconds.
+    fn hop_seconds(&self) -> f32 {
+        HOP as f32 / self.sample_rate as f32
+    }
+
+    /// measure the spectral flux of the oldest pending window.
+    fn analyze_hop(&mut self) {
+        let mut buffer: Vec<Complex<f32>> = self.pending[..WINDOW]
+            .iter()
+            .zip(&self.window)
+            .map(|(s, w)| Complex::new(s * w, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+        let mut flux = 0.0;
+        for (bin, previous) in buffer.iter().zip(self.previous.iter_mut()) {
+            // log compression keeps loud and quiet passages comparable
+            let magnitude = (1.0 + 100.0 * bin.norm()).ln();
+            flux += (magnitude - *previous).max(0.0);
+            *previous = magnitude;
+        }
+        let keep = (TEMPO_SECONDS / self.hop_seconds()) as usize;
+        self.flux.push_back(flux / (WINDOW / 2) as f32);
+        while self.flux.len() > keep {
+            self.flux.pop_front();
+        }
+    }
+
+    /// Look for an onset in the hop before the newest one,
+    /// and decide whether it is a beat.
+    fn pick(&mut self) -> Option<Beat> {
+        let len = self.flux.len();
+        if len < 3 {
+            return None;
+        }
+        let (before, onset, after) = (self.flux[len - 3], self.flux[len - 2], self.flux[len - 1]);
+        let recent = ((THRESHOLD_SECONDS / self.hop_seconds()) as usize).min(len);
+        let window = self.flux.iter().skip(len - recent);
+        let mean = window.clone().sum::<f32>() / recent as f32;
+        let deviation = (window.map(|f| (f - mean).powi(2)).sum::<f32>() / recent as f32).sqrt();
+        let threshold = mean + deviation + 1e-3;
+        if onset <= before || onset < after || onset <= threshold {
+            return None;
+        }
+
+        self.estimate_tempo();
+        // the onset happened at the start of the hop before the newest one
+        let time = self
+            .time(self.offset.saturating_sub(2 * HOP as u64))
+            .as_secs_f32();
+        let strength = 1.0 - threshold / onset;
+        let confidence = match (self.tempo, self.last_beat) {
+            (Some((bpm, certainty)), Some(last)) => {
+                let period = 60.0 / bpm;
+                let beats = (time - last) / period;
+                // too close to the last beat to be the next one, e.g. an off-beat hi-hat
+                if beats < 0.5 {
+                    return None;
+                }
+                let error = (beats - beats.round()).abs() * 2.0;
+                strength * certainty * (1.0 - error)
+            }
+            // without a tempo every onset is a guess
+            _ => strength * 0.5,
+        };
+        self.last_beat = Some(time);
+        Some(Beat {
+            time: Duration::from_secs_f32(time),
+            confidence: confidence.clamp(0.0, 1.0),
+            bpm: self.bpm(),
+        })
+    }
+
+    /// estimate the tempo from the autocorrelation of the onset strength,
+    /// once at least two seconds of the stream have been heard.
+    fn estimate_tempo(&mut self) {
+        let hop = self.hop_seconds();
+        let len = self.flux.len();
+        if (len as f32) * hop < 2.0 {
+            return;
+        }
+        let mean = self.flux.iter().sum::<f32>() / len as f32;
+        let centered: Vec<f32> = self.flux.iter().map(|f| f - mean).collect();
+        let correlation = |lag: usize| {
+            let sum: f32 = centered[lag..]
+                .iter()
+                .zip(&centered)
+                .map(|(a, b)| a * b)
+                .sum();
+            sum / (len - lag) as f32
+        };
+        let energy = correlation(0);
+        if energy <= 0.0 {
+            return;
+        }
+        let shortest = (60.0 / MAX_BPM / hop).floor() as usize;
+        let longest = ((60.0 / MIN_BPM / hop).ceil() as usize).min(len / 2);
+        // weigh each lag by how likely its tempo is, centered on 120bpm with a spread of
+        // one octave, so that halving or doubling the tempo does not win a close call
+        let prior = |lag: usize| {
+            let octaves = (60.0 / (lag as f32 * hop) / 120.0).log2();
+            (-0.5 * octaves * octaves).exp()
+        };
+        let scores: Vec<f32> = (shortest.max(1) - 1..=longest + 1)
+            .map(|lag| correlation(lag) * prior(lag))
+            .collect();
+        let best = (1..scores.len() - 1)
+            .max_by(|a, b| scores[*a].partial_cmp(&scores[*b]).unwrap())
+            .unwrap();
+        if scores[best] <= 0.0 {
+            return;
+        }
+        // fit a parabola through the peak and its neighbours for a finer lag
+        let (left, peak, right) = (scores[best - 1], scores[best], scores[best + 1]);
+        let curvature = left - 2.0 * peak + right;
+        let offset = if curvature < 0.0 {
+            0.5 * (left - right) / curvature
+        } else {
+            0.0
+        };
+        let lag = (shortest.max(1) - 1 + best) as f32 + offset;
+        let certainty = correlation(shortest.max(1) - 1 + best) / energy;
+        self.tempo = Some((60.0 / (lag * hop), certainty.min(1.0)));
+    }
+}
+
+impl Default for BeatTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `seconds` of a stereo click track at `bpm`, cut into mp3-sized frames.
+/// Each click is a short burst of a decaying 2kHz tone.
+#[cfg(test)]
+fn click_track(bpm: f32, seconds: f32) -> Vec<Frame> {
+    let rate = 44100.0;
+    let period = (60.0 / bpm * rate) as usize;
+    let samples: Vec<i16> = (0..(seconds * rate) as usize)
+        .flat_map(|i| {
+            let t = (i % period) as f32 / rate;
+            let click = (2.0 * PI * 2000.0 * t).sin() * (-t * 200.0).exp();
+            let sample = (click * 20000.0) as i16;
+            vec![sample, sample]
+        })
+        .collect();
+    samples
+        .chunks(1152 * 2)
+        .map(|data| Frame {
+            data: data.to_vec(),
+            sample_rate: 44100,
+            channels: 2,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn track(frames: &[Frame]) -> (BeatTracker, Vec<Beat>) {
+    let mut tracker = BeatTracker::new();
+    let mut start = Duration::from_secs(0);
+    let mut beats = Vec::new();
+    for frame in frames {
+        beats.extend(tracker.process(frame, start));
+        start += frame.duration();
+    }
+    (tracker, beats)
+}
+
+#[cfg(test)]
+#[test]
+fn test_tempo() {
+    for bpm in [90.0, 120.0, 128.0].iter() {
+        let (tracker, _) = track(&click_track(*bpm, 8.0));
+        let estimate = tracker.bpm().unwrap();
+        assert!(
+            (estimate - bpm).abs() < 2.0,
+            "{} estimated as {}",
+            bpm,
+            estimate
+        );
+    }
+}
+#[test]
+fn test_beats() {
+    let (_, beats) = track(&click_track(120.0, 8.0));
+    // one click every half second; the first is lost while the tracker warms up
+    assert!(
+        beats.len() >= 14 && beats.len() <= 16,
+        "{} beats",
+        beats.len()
+    );
+    for pair in beats.windows(2) {
+        let gap = (pair[1].time - pair[0].time).as_secs_f32();
+        assert!((gap - 0.5).abs() < 0.03, "beats {:?} apart", gap);
+    }
+    let last = beats.last().unwrap();
+    assert!(last.confidence > 0.5);
+    assert!((last.bpm.unwrap() - 120.0).abs() < 2.0);
+}
+#[test]
+fn test_silence() {
+    let silence = vec![
+        Frame {
+            data: vec![0; 1152 * 2],
+            sample_rate: 44100,
+            channels: 2,
+        };
+        200
+    ];
+    let (tracker, beats) = track(&silence);
+    assert!(beats.is_empty());
+    assert!(tracker.bpm().is_none());
+}
+#[test]
+fn test_seek() {
+    let frames = click_track(120.0, 8.0);
+    let mut tracker = BeatTracker::new();
+    let mut start = Duration::from_secs(0);
+    let mut beats = Vec::new();
+    for (i, frame) in frames.iter().enumerate() {
+        // halfway through, the stream jumps a minute ahead
+        if i == frames.len() / 2 {
+            start += Duration::from_secs(60);
+        }
+        beats.extend(tracker.process(frame, start));
+        start += frame.duration();
+    }
+    let later: Vec<_> = beats
+        .iter()
+        .map(|beat| beat.time.as_secs_f32() - 60.0)
+        .filter(|time| *time > 0.0)
+        .collect();
+    assert!(!later.is_empty());
+    // the clicks are still on the half-second grid of where the stream jumped to
+    for time in later {
+        let off = (time + 0.25) % 0.5 - 0.25;
+        assert!(off.abs() < 0.03, "beat at {}", time);
+    }
+}
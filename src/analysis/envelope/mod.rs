@@ -0,0 +1,90 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::time::Duration;
+
+/// `Envelope` follows a signal smoothly, the way a VU meter does:
+/// it rises towards the signal with the `attack` time constant and falls with the `release`
+/// time constant, so that short peaks still register but flicker is smoothed away.
+///
+/// # Examples
+/// ```
+/// # use kartina::analysis::envelope::Envelope;
+/// # use std::time::Duration;
+/// let mut envelope = Envelope::new(Duration::from_millis(10), Duration::from_millis(300));
+/// let level = envelope.follow(1.0, Duration::from_millis(26));
+/// assert!(level > 0.9);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope {
+    attack: f32,
+    release: f32,
+    value: f32,
+}
+
+impl Envelope {
+    pub fn new(attack: Duration, release: Duration) -> Self {
+        Self {
+            attack: attack.as_secs_f32(),
+            release: release.as_secs_f32(),
+            value: 0.0,
+        }
+    }
+
+    /// Move towards `target` for `elapsed` time, returning the new value.
+    pub fn follow(&mut self, target: f32, elapsed: Duration) -> f32 {
+        let constant = if target > self.value {
+            self.attack
+        } else {
+            self.release
+        };
+        // a time constant of zero jumps straight to the target
+        let rate = if constant > 0.0 {
+            1.0 - (-elapsed.as_secs_f32() / constant).exp()
+        } else {
+            1.0
+        };
+        self.value += rate * (target - self.value);
+        self.value
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_attack_release() {
+    let step = Duration::from_millis(10);
+    let mut envelope = Envelope::new(Duration::from_millis(20), Duration::from_millis(200));
+    // rising is quick
+    for _ in 0..10 {
+        envelope.follow(1.0, step);
+    }
+    assert!(envelope.value > 0.99);
+    // falling is slow
+    let fallen = envelope.follow(0.0, step);
+    assert!(fallen > 0.9 && fallen < 1.0);
+    for _ in 0..200 {
+        envelope.follow(0.0, step);
+    }
+    assert!(envelope.value < 0.01);
+}
+#[test]
+fn test_instant() {
+    let mut envelope = Envelope::new(Duration::from_secs(0), Duration::from_secs(0));
+    assert_eq!(envelope.follow(0.5, Duration::from_millis(1)), 0.5);
+    assert_eq!(envelope.follow(0.25, Duration::from_millis(1)), 0.25);
+}
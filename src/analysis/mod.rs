@@ -0,0 +1,262 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::audio::Frame;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{f32::consts::PI, sync::Arc, time::Duration};
+
+pub mod beat;
+pub mod envelope;
+
+/// number of log-spaced frequency bands in `Features::bands`.
+pub const BANDS: usize = 16;
+
+/// lowest and highest frequencies covered by the bands, in hertz.
+const LOW: f32 = 20.0;
+const HIGH: f32 = 16000.0;
+
+/// upper edges of the bass and mid ranges, in hertz.
+const BASS: f32 = 250.0;
+const MID: f32 = 4000.0;
+
+/// dynamic range mapped onto levels between 0.0 and 1.0:
+/// anything quieter than -60dB full scale is 0.0.
+const FLOOR_DB: f32 = -60.0;
+
+/// Musical features of a single decoded frame.
+///
+/// Levels (`bands`, `bass`, `mid`, `treble`) are on a decibel scale
+/// between 0.0 (at or below -60dB full scale) and 1.0 (full scale),
+/// so that they can be used directly as color or size values.
+#[derive(Clone, Debug, Default)]
+pub struct Features {
+    /// magnitude spectrum of the mono mix, from 0Hz up to the Nyquist frequency.
+    /// A full-scale sine has a peak magnitude of 1.0.
+    pub spectrum: Vec<f32>,
+    /// frequency covered by each bin of `spectrum`, in hertz.
+    pub bin_width: f32,
+    /// levels of log-spaced bands between 20Hz and 16kHz.
+    pub bands: [f32; BANDS],
+    /// level of everything below 250Hz.
+    pub bass: f32,
+    /// level between 250Hz and 4kHz.
+    pub mid: f32,
+    /// level above 4kHz.
+    pub treble: f32,
+    /// root mean square of each channel, between 0.0 and 1.0.
+    pub rms: Vec<f32>,
+    /// how much audio the frame held.
+    pub duration: Duration,
+}
+
+impl Features {
+    /// largest magnitude in the spectrum between `low` and `high` hertz.
+    fn peak(&self, low: f32, high: f32) -> f32 {
+        let first = (low / self.bin_width).ceil() as usize;
+        let last = ((high / self.bin_width).floor() as usize).min(self.spectrum.len() - 1);
+        self.spectrum
+            .get(first..=last.max(first))
+            .map(|bins| bins.iter().cloned().fold(0.0, f32::max))
+            .unwrap_or(0.0)
+    }
+
+    /// loudness of the frame: the mean of the channels' rms.
+    pub fn loudness(&self) -> f32 {
+        self.rms.iter().sum::<f32>() / self.rms.len().max(1) as f32
+    }
+}
+
+/// convert a linear magnitude into a level between 0.0 and 1.0.
+fn level(magnitude: f32) -> f32 {
+    if magnitude <= 0.0 {
+        return 0.0;
+    }
+    let db = 20.0 * magnitude.log10();
+    ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0)
+}
+
+/// `Analyzer` turns decoded frames into `Features`.
+/// Each frame is mixed down to mono, multiplied by a Hann window,
+/// zero-padded to the FFT size and transformed.
+pub struct Analyzer {
+    fft: Arc<dyn Fft<f32>>,
+    buffer: Vec<Complex<f32>>,
+}
+
+impl Analyzer {
+    /// Create an analyzer whose FFT covers `size` samples.
+    /// Frames longer than `size` are truncated.
+    pub fn new(size: usize) -> Self {
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(size),
+            buffer: vec![Complex::default(); size],
+        }
+    }
+
+    /// Extract the `Features` of `frame`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use kartina::{audio::Frame, Analyzer};
+    /// # let frame = Frame { data: vec![0; 2304], sample_rate: 44100, channels: 2 };
+    /// let mut analyzer = Analyzer::new(2048);
+    /// let features = analyzer.analyze(&frame);
+    /// assert!(features.bass >= 0.0 && features.bass <= 1.0);
+    /// ```
+    pub fn analyze(&mut self, frame: &Frame) -> Features {
+        let channels = frame.channels.max(1);
+        let size = self.buffer.len();
+        let length = (frame.data.len() / channels).min(size);
+
+        // mono mix, windowed, zero-padded
+        let mut gain = 0.0;
+        for (i, value) in self.buffer.iter_mut().enumerate() {
+            *value = Complex::default();
+            if i < length {
+                let window = 0.5 - 0.5 * (2.0 * PI * i as f32 / length as f32).cos();
+                let samples = &frame.data[i * channels..(i + 1) * channels];
+                let mono = samples.iter().map(|s| *s as f32).sum::<f32>() / channels as f32;
+                value.re = window * mono / 32768.0;
+                gain += window;
+            }
+        }
+        self.fft.process(&mut self.buffer);
+
+        // a sine of amplitude A peaks at A * sum(window) / 2
+        let scale = if gain > 0.0 { 2.0 / gain } else { 0.0 };
+        let spectrum: Vec<f32> = self.buffer[..=size / 2]
+            .iter()
+            .map(|bin| bin.norm() * scale)
+            .collect();
+        let rms = (0..channels)
+            .map(|channel| {
+                let samples = frame.data.iter().skip(channel).step_by(channels);
+                let count = frame.data.len() / channels;
+                let sum: f32 = samples.map(|s| (*s as f32 / 32768.0).powi(2)).sum();
+                (sum / count.max(1) as f32).sqrt()
+            })
+            .collect();
+        let mut features = Features {
+            spectrum,
+            bin_width: frame.sample_rate.max(1) as f32 / size as f32,
+            rms,
+            duration: frame.duration(),
+            ..Features::default()
+        };
+
+        let ratio = (HIGH / LOW).powf(1.0 / BANDS as f32);
+        for i in 0..BANDS {
+            let low = LOW * ratio.powi(i as i32);
+            features.bands[i] = level(features.peak(low, low * ratio));
+        }
+        let nyquist = features.bin_width * (size / 2) as f32;
+        features.bass = level(features.peak(LOW, BASS));
+        features.mid = level(features.peak(BASS, MID));
+        features.treble = level(features.peak(MID, nyquist));
+        features
+    }
+}
+
+impl Default for Analyzer {
+    /// an analyzer large enough for a whole mp3 frame (1152 samples per channel).
+    fn default() -> Self {
+        Self::new(2048)
+    }
+}
+
+/// one mp3 frame of a stereo sine at `hz`, with the left channel at `amplitude`
+/// and the right channel silent.
+#[cfg(test)]
+fn sine(hz: f32, amplitude: f32) -> Frame {
+    let data = (0..1152)
+        .flat_map(|i| {
+            let t = i as f32 / 44100.0;
+            let left = amplitude * 32767.0 * (2.0 * PI * hz * t).sin();
+            vec![left as i16, 0]
+        })
+        .collect();
+    Frame {
+        data,
+        sample_rate: 44100,
+        channels: 2,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_level() {
+    assert_eq!(level(0.0), 0.0);
+    assert_eq!(level(1.0), 1.0);
+    assert!((level(0.001) - 0.0).abs() < 1e-6);
+    assert!((level(0.1) - 2.0 / 3.0).abs() < 1e-6);
+}
+#[test]
+fn test_spectrum_peak() {
+    let mut analyzer = Analyzer::default();
+    let features = analyzer.analyze(&sine(1000.0, 1.0));
+    assert_eq!(features.spectrum.len(), 1025);
+    let (bin, magnitude) = features
+        .spectrum
+        .iter()
+        .enumerate()
+        .fold(
+            (0, 0.0),
+            |best, (i, m)| if *m > best.1 { (i, *m) } else { best },
+        );
+    assert!((bin as f32 * features.bin_width - 1000.0).abs() < features.bin_width);
+    // the mono mix halves the amplitude of a sine in one channel only
+    assert!((magnitude - 0.5).abs() < 0.1);
+}
+#[test]
+fn test_ranges() {
+    let mut analyzer = Analyzer::default();
+    let low = analyzer.analyze(&sine(80.0, 0.8));
+    assert!(low.bass > 0.8);
+    assert!(low.treble < 0.3);
+    let high = analyzer.analyze(&sine(8000.0, 0.8));
+    assert!(high.treble > 0.8);
+    assert!(high.bass < 0.3);
+    // the loudest band is the one holding the sine
+    let loudest = |bands: &[f32; BANDS]| {
+        (0..BANDS)
+            .max_by(|a, b| bands[*a].partial_cmp(&bands[*b]).unwrap())
+            .unwrap()
+    };
+    assert!(loudest(&low.bands) < loudest(&high.bands));
+}
+#[test]
+fn test_rms() {
+    let mut analyzer = Analyzer::default();
+    let features = analyzer.analyze(&sine(440.0, 1.0));
+    assert_eq!(features.rms.len(), 2);
+    // the rms of a sine is its amplitude over the square root of two
+    assert!((features.rms[0] - 0.707).abs() < 0.01);
+    assert_eq!(features.rms[1], 0.0);
+    assert!((features.loudness() - 0.354).abs() < 0.01);
+    assert_eq!(features.duration.as_micros(), 26_122);
+}
+#[test]
+fn test_silence() {
+    let mut analyzer = Analyzer::default();
+    let features = analyzer.analyze(&sine(440.0, 0.0));
+    assert_eq!(features.bands, [0.0; BANDS]);
+    assert_eq!(
+        (features.bass, features.mid, features.treble),
+        (0.0, 0.0, 0.0)
+    );
+}
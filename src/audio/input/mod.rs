@@ -0,0 +1,335 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use super::source::{AudioSource, Frame, FRAME_SAMPLES};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+};
+
+/// number of frames the device may capture ahead of the source before the oldest samples
+/// are dropped (~100ms at 44.1kHz), which bounds how far the visuals lag behind the room.
+const BEHIND: usize = 4;
+
+/// Samples captured and not yet read, in room that is set aside once, when capture starts.
+struct Buffer {
+    samples: VecDeque<i16>,
+    limit: usize,
+    // set once the device is gone
+    closed: bool,
+}
+
+struct Shared {
+    buffer: Mutex<Buffer>,
+    arrived: Condvar,
+}
+
+/// The end of the buffer the device pushes into, from its own thread.
+/// The buffer is closed once this is dropped.
+struct Capture(Arc<Shared>);
+
+impl Capture {
+    /// Convert `data` to 16 bits and add it to the buffer, dropping the oldest samples
+    /// to make room. This never waits and never allocates, so that the device is not held up:
+    /// while the source is taking samples out, `data` is dropped instead.
+    fn push<T: cpal::Sample>(&self, data: &[T]) {
+        let mut buffer = match self.0.buffer.try_lock() {
+            Ok(buffer) => buffer,
+            Err(_) => return,
+        };
+        let data = &data[data.len().saturating_sub(buffer.limit)..];
+        let excess = (buffer.samples.len() + data.len()).saturating_sub(buffer.limit);
+        buffer.samples.drain(..excess);
+        buffer.samples.extend(data.iter().map(cpal::Sample::to_i16));
+        self.0.arrived.notify_one();
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.0.buffer.lock().unwrap().closed = true;
+        self.0.arrived.notify_one();
+    }
+}
+
+/// `AudioSource` that captures whatever an input device hears:
+/// a microphone, a line-in, or a loopback device that carries what another program is playing.
+///
+/// The device hands over samples in chunks of its own size, from a thread of its own.
+/// They are gathered into frames of `FRAME_SAMPLES`, so that the analysis and the renderer
+/// cannot tell live audio from a decoded file. The stream ends if the device goes away.
+///
+/// The device is never held up: once `BEHIND` frames are waiting to be read,
+/// the oldest samples are dropped, so that a reader that fell behind catches up with the room.
+pub struct InputSource {
+    shared: Arc<Shared>,
+    sample_rate: u32,
+    channels: usize,
+    // the device is released once this is dropped, along with the source
+    _stop: Option<mpsc::Sender<()>>,
+}
+
+impl InputSource {
+    /// Start capturing from the input device called `name`,
+    /// or from the default input device when `name` is `None`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use kartina::audio::{input::InputSource, AudioSource};
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut source = InputSource::open(None)?;
+    /// let frame = source.next_frame()?.unwrap();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open(name: Option<&str>) -> io::Result<Self> {
+        let name = name.map(str::to_string);
+        let (ready, started) = mpsc::channel();
+        let (stop, stopped) = mpsc::channel::<()>();
+        // streams cannot always be moved between threads,
+        // so the stream lives on a thread that only waits for the source to be dropped
+        thread::spawn(move || match capture(name.as_deref()) {
+            Ok((stream, source)) => {
+                let _ = ready.send(Ok(source));
+                let _ = stopped.recv();
+                drop(stream);
+            }
+            Err(e) => {
+                let _ = ready.send(Err(e));
+            }
+        });
+        let mut source = started
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "the capture thread panicked"))??;
+        log::info!(
+            "capturing {} channels at {}Hz",
+            source.channels,
+            source.sample_rate
+        );
+        source._stop = Some(stop);
+        Ok(source)
+    }
+
+    /// Create a source over the interleaved samples pushed into the `Capture` returned with it,
+    /// which ends once the `Capture` is gone. Room is set aside for `limit` samples.
+    fn new(sample_rate: u32, channels: usize, limit: usize) -> (Capture, Self) {
+        let shared = Arc::new(Shared {
+            buffer: Mutex::new(Buffer {
+                samples: VecDeque::with_capacity(limit),
+                limit,
+                closed: false,
+            }),
+            arrived: Condvar::new(),
+        });
+        let source = Self {
+            shared: shared.clone(),
+            sample_rate,
+            channels,
+            _stop: None,
+        };
+        (Capture(shared), source)
+    }
+}
+
+impl AudioSource for InputSource {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let count = FRAME_SAMPLES * self.channels;
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        while buffer.samples.len() < count && !buffer.closed {
+            buffer = self.shared.arrived.wait(buffer).unwrap();
+        }
+        // once the device is gone, whatever is left makes the last frame
+        if buffer.samples.is_empty() {
+            return Ok(None);
+        }
+        let count = count.min(buffer.samples.len());
+        Ok(Some(Frame {
+            data: buffer.samples.drain(..count).collect(),
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+        }))
+    }
+}
+
+/// Open the input device called `name`, or the default one, and start it.
+/// Returns the stream along with the source that reads what it captures.
+fn capture(name: Option<&str>) -> io::Result<(cpal::Stream, InputSource)> {
+    let host = cpal::default_host();
+    let device = match name {
+        None => host.default_input_device().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "there is no default input device")
+        })?,
+        Some(name) => find(&host, name)?,
+    };
+    let supported = device.default_input_config().map_err(other)?;
+    let config = supported.config();
+    let channels = config.channels as usize;
+    let limit = BEHIND * FRAME_SAMPLES * channels;
+    let (capture, source) = InputSource::new(config.sample_rate.0, channels, limit);
+    let stream = match supported.sample_format() {
+        cpal::SampleFormat::I16 => build::<i16>(&device, &config, capture),
+        cpal::SampleFormat::U16 => build::<u16>(&device, &config, capture),
+        cpal::SampleFormat::F32 => build::<f32>(&device, &config, capture),
+    }
+    .map_err(other)?;
+    stream.play().map_err(other)?;
+    Ok((stream, source))
+}
+
+/// wrap an error of cpal in an `io::Error`.
+fn other<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Look up the input device called `name`.
+/// When there is none, the error lists the names of the devices there are.
+fn find(host: &cpal::Host, name: &str) -> io::Result<cpal::Device> {
+    let mut names = Vec::new();
+    for device in host.input_devices().map_err(other)? {
+        match device.name() {
+            Ok(n) if n == name => return Ok(device),
+            Ok(n) => names.push(n),
+            Err(_) => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "there is no input device called '{}'; the input devices are: {}",
+            name,
+            names.join(", ")
+        ),
+    ))
+}
+
+/// build a stream that pushes the samples of `device` into `capture`.
+fn build<T: cpal::Sample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    capture: Capture,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| capture.push(data),
+        |e| log::error!("audio input failed: {}", e),
+    )
+}
+
+/// `InputSource` over `chunks` chunks of `size` samples of a mono 440Hz sine,
+/// all captured before it is read, standing in for a device.
+#[cfg(test)]
+fn sine(chunks: usize, size: usize) -> InputSource {
+    // room for every chunk, so that none are dropped however slowly they are read
+    let (device, source) = InputSource::new(44100, 1, chunks * size);
+    for chunk in 0..chunks {
+        let data: Vec<i16> = (chunk * size..(chunk + 1) * size)
+            .map(|i| {
+                let t = i as f32 / 44100.0;
+                ((t * 440.0 * std::f32::consts::TAU).sin() * 16384.0) as i16
+            })
+            .collect();
+        device.push(&data);
+    }
+    source
+}
+
+#[cfg(test)]
+#[test]
+fn test_regroup() {
+    // 10 chunks of 512 samples make four whole frames and the rest
+    let mut source = sine(10, 512);
+    let mut frames = Vec::new();
+    while let Some(frame) = source.next_frame().unwrap() {
+        frames.push(frame);
+    }
+    assert_eq!(frames.len(), 5);
+    assert!(frames[..4].iter().all(|frame| frame.samples() == 1152));
+    assert_eq!(frames[4].samples(), 5120 - 4 * 1152);
+    assert_eq!((frames[0].sample_rate, frames[0].channels), (44100, 1));
+    // no sample is lost or repeated at the edges of the chunks
+    let whole = sine(1, 5120).next_frame().unwrap().unwrap();
+    let samples: Vec<i16> = frames.iter().flat_map(|frame| frame.data.clone()).collect();
+    assert_eq!(samples[..1152], whole.data[..]);
+    assert_eq!(samples.len(), 5120);
+}
+#[test]
+fn test_live_pipeline() {
+    use super::{sink::NullSink, Pipeline};
+    use crate::{analysis::Analyzer, timing::Clock};
+
+    let mut pipeline = Pipeline::spawn(sine(10, 512), Box::new(NullSink));
+    let clock = pipeline.clock();
+    let mut analyzer = Analyzer::default();
+    let mut loudness = 0.0;
+    while let Some(frame) = pipeline.next_frame().unwrap() {
+        loudness += analyzer.analyze(&frame).loudness();
+    }
+    pipeline.finish().unwrap();
+    assert!(loudness > 0.0);
+    assert_eq!(clock.now().as_micros(), 116_099);
+}
+#[test]
+fn test_stalled_device() {
+    use super::{sink::NullSink, Pipeline};
+    use crate::timing::{FrameScheduler, Tick};
+    use std::time::Duration;
+
+    let (device, source) = InputSource::new(44100, 1, FRAME_SAMPLES);
+    let pipeline = Pipeline::spawn(source, Box::new(NullSink));
+    let mut scheduler = FrameScheduler::new(pipeline);
+    // nothing was captured yet, and the window carries on drawing what it has
+    assert!(matches!(
+        scheduler.tick(Duration::from_secs(1)).unwrap(),
+        Tick::Repeat
+    ));
+    // once the device has gone, the end of the stream comes through without waiting either
+    drop(device);
+    let mut tick = scheduler.tick(Duration::from_secs(1)).unwrap();
+    while matches!(tick, Tick::Repeat) {
+        thread::yield_now();
+        tick = scheduler.tick(Duration::from_secs(1)).unwrap();
+    }
+    assert!(matches!(tick, Tick::End));
+    scheduler.source_mut().finish().unwrap();
+}
+#[test]
+fn test_overrun() {
+    // a reader that fell behind picks up with the most recent samples
+    let (device, mut source) = InputSource::new(44100, 1, BEHIND * FRAME_SAMPLES);
+    for chunk in 0..10 {
+        device.push(&[chunk as i16; 1152]);
+    }
+    drop(device);
+    let frame = source.next_frame().unwrap().unwrap();
+    assert_eq!(frame.data[0], 10 - BEHIND as i16);
+}
+#[test]
+fn test_contended() {
+    let (device, mut source) = InputSource::new(44100, 1, FRAME_SAMPLES);
+    // while the source is taking samples out, what the device captures is dropped
+    let reading = source.shared.buffer.lock().unwrap();
+    device.push(&[1i16; 1152]);
+    drop(reading);
+    device.push(&[2i16; 1152]);
+    drop(device);
+    assert_eq!(source.next_frame().unwrap().unwrap().data, vec![2; 1152]);
+    assert!(source.next_frame().unwrap().is_none());
+}
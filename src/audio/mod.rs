@@ -0,0 +1,610 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    playlist::{Playlist, PlaylistSource},
+    timing::{AudioClock, Clock},
+};
+use std::{
+    io, mem,
+    sync::{mpsc::TryRecvError, Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+pub mod input;
+mod ring;
+pub mod sink;
+pub mod source;
+
+pub use sink::Sink;
+pub use source::{AudioSource, Frame};
+
+/// number of frames the decoder may run ahead of the audio output (~1.7s at 44.1kHz).
+const CAPACITY: usize = 64;
+
+/// number of frames an input device may run ahead of the audio output (~100ms at 44.1kHz);
+/// what it hears is seen this long after at most, on top of what the device itself holds.
+const LIVE_CAPACITY: usize = 4;
+
+/// How far playback has got, as of the last frame handed to the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// number of the track being played, counting from the first one.
+    pub track: u64,
+    /// position within that track.
+    pub position: Duration,
+    /// length of that track, if the source knows it.
+    pub duration: Option<Duration>,
+}
+
+/// Something the listener asked the decoder to do.
+enum Request {
+    Seek(Duration),
+    NextTrack,
+    PreviousTrack,
+}
+
+/// Requests waiting for the decoder, along with the epoch they start.
+/// Every request begins a new epoch; frames decoded in an earlier one are dropped
+/// by the output and the visualizer, so that neither plays what came before the request.
+#[derive(Default)]
+struct Control {
+    epoch: u64,
+    requests: Vec<Request>,
+    // set once the output has failed, which ends decoding as well
+    stopped: bool,
+}
+
+/// A decoded frame on its way through the ring buffer.
+struct Item {
+    epoch: u64,
+    // when the frame is heard, on the clock of the output
+    start: Duration,
+    // where the frame starts
+    progress: Progress,
+    frame: Frame,
+}
+
+/// `Pipeline` decodes a song exactly once and shares the frames
+/// between the audio output and the visualizer.
+///
+/// One thread decodes frames into a ring buffer. A second thread hands them to a `Sink`,
+/// advancing the `AudioClock` as it goes, while the visualizer reads the same frames
+/// through the pipeline's `AudioSource` implementation. The output paces the decoder;
+/// the visualizer is allowed to fall behind and skip frames without stalling the audio.
+/// Every frame is stamped with when it will be heard, so that a visualizer that skipped
+/// some still knows where the frames it does read belong.
+///
+/// Seeking and moving through the playlist are passed to the decoder as requests.
+/// Whatever was decoded before a request is dropped from the ring buffer and from the sink,
+/// so that the output and the visualizer both continue from the new position.
+///
+/// If the output fails, the clock stops with it, so the error is handed to the visualizer
+/// by the next frame it asks for, or returned by `finish` if it never asks.
+pub struct Pipeline {
+    frames: ring::Reader<Item>,
+    // taken from the ring by `is_ready`, and not yet handed out
+    next: Option<Arc<Item>>,
+    // track of the last frame handed to the visualizer, when it is heard, and where it starts
+    track: u64,
+    start: Duration,
+    position: Duration,
+    clock: AudioClock,
+    control: Arc<Mutex<Control>>,
+    progress: Arc<Mutex<Progress>>,
+    paused: Arc<(Mutex<bool>, Condvar)>,
+    failed: Arc<Mutex<Option<io::Error>>>,
+    threads: Vec<JoinHandle<io::Result<()>>>,
+}
+
+impl Pipeline {
+    /// Start playing the tracks of `playlist` one after another, without gaps, through `sink`.
+    /// The playlist can be moved through while it plays with `next_track` and `previous_track`.
+    pub fn play(playlist: Playlist, sink: Box<dyn Sink>) -> io::Result<Self> {
+        Ok(Self::spawn(PlaylistSource::new(playlist)?, sink))
+    }
+
+    /// Start visualizing what the input device called `device` hears,
+    /// or the default input device when `device` is `None`, sending it on to `sink`.
+    pub fn listen(device: Option<&str>, sink: Box<dyn Sink>) -> io::Result<Self> {
+        let source = input::InputSource::open(device)?;
+        Ok(Self::with_capacity(source, sink, LIVE_CAPACITY))
+    }
+
+    /// Start a pipeline over the frames produced by `source`.
+    pub fn spawn<S>(source: S, sink: Box<dyn Sink>) -> Self
+    where
+        S: AudioSource + Send + 'static,
+    {
+        Self::with_capacity(source, sink, CAPACITY)
+    }
+
+    /// Start a pipeline whose decoder runs up to `capacity` frames ahead of the output.
+    fn with_capacity<S>(mut source: S, mut sink: Box<dyn Sink>, capacity: usize) -> Self
+    where
+        S: AudioSource + Send + 'static,
+    {
+        let writer = ring::channel(capacity);
+        let output = writer.reader(false);
+        let frames = writer.reader(true);
+        let clock = AudioClock::new();
+        let control = Arc::new(Mutex::new(Control::default()));
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let paused = Arc::new((Mutex::new(false), Condvar::new()));
+        let failed = Arc::new(Mutex::new(None));
+
+        let requested = control.clone();
+        let heard = clock.clone();
+        let decoder = thread::spawn(move || {
+            let mut track = source.track();
+            let mut position = Duration::from_secs(0);
+            // when the next frame is heard
+            let mut start = Duration::from_secs(0);
+            loop {
+                let (epoch, requests) = {
+                    let mut control = requested.lock().unwrap();
+                    if control.stopped {
+                        return Ok(());
+                    }
+                    (control.epoch, mem::take(&mut control.requests))
+                };
+                if !requests.is_empty() {
+                    // what was decoded ahead is dropped, so the next frame is heard
+                    // as soon as the output is done with the one it is playing
+                    start = heard.now();
+                }
+                for request in requests {
+                    let result = match request {
+                        Request::Seek(to) => source.seek(to).map(|start| position = start),
+                        Request::NextTrack => source.next_track(),
+                        Request::PreviousTrack => source.previous_track(),
+                    };
+                    if let Err(e) = result {
+                        log::warn!("{}", e);
+                    }
+                }
+                match source.next_frame()? {
+                    Some(frame) => {
+                        let number = source.track();
+                        if number != track {
+                            track = number;
+                            position = Duration::from_secs(0);
+                        }
+                        let progress = Progress {
+                            track: number,
+                            position,
+                            duration: source.duration(),
+                        };
+                        position += frame.duration();
+                        let item = Item {
+                            epoch,
+                            start,
+                            progress,
+                            frame,
+                        };
+                        start += item.frame.duration();
+                        writer.push(item);
+                    }
+                    None => return Ok(()),
+                }
+            }
+        });
+        let played = clock.clone();
+        let (requested, heard, pause) = (control.clone(), progress.clone(), paused.clone());
+        let failure = failed.clone();
+        let player = thread::spawn(move || {
+            if let Err(e) = play(output, &mut *sink, &played, &requested, &heard, &pause) {
+                requested.lock().unwrap().stopped = true;
+                *failure.lock().unwrap() = Some(e);
+            }
+            Ok(())
+        });
+        Self {
+            frames,
+            next: None,
+            track: 0,
+            start: Duration::from_secs(0),
+            position: Duration::from_secs(0),
+            clock,
+            control,
+            progress,
+            paused,
+            failed,
+            threads: vec![decoder, player],
+        }
+    }
+
+    /// the clock that follows the audio output.
+    pub fn clock(&self) -> AudioClock {
+        self.clock.clone()
+    }
+
+    /// how far playback has got into the track being heard.
+    pub fn progress(&self) -> Progress {
+        *self.progress.lock().unwrap()
+    }
+
+    /// Stop the output where it is, or carry on from there.
+    /// The decoder keeps the ring buffer full meanwhile, so that playback resumes right away.
+    pub fn set_paused(&self, paused: bool) {
+        let (lock, changed) = &*self.paused;
+        *lock.lock().unwrap() = paused;
+        changed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.0.lock().unwrap()
+    }
+
+    /// Continue playback from `position` in the track being heard.
+    /// Sources that cannot seek, such as an input device, log a warning and carry on.
+    pub fn seek(&mut self, position: Duration) {
+        self.request(Request::Seek(position));
+        self.progress.lock().unwrap().position = position;
+    }
+
+    /// Skip to the next track of the playlist.
+    /// On the last track of a playlist that does not repeat, this ends playback.
+    pub fn next_track(&mut self) {
+        self.request(Request::NextTrack);
+    }
+
+    /// Go back to the track before the one that is playing, or start the first track over.
+    pub fn previous_track(&mut self) {
+        self.request(Request::PreviousTrack);
+    }
+
+    /// Hand `request` to the decoder, dropping the frames it decoded ahead,
+    /// so that the change is heard and seen right away.
+    fn request(&mut self, request: Request) {
+        let mut control = self.control.lock().unwrap();
+        control.requests.push(request);
+        control.epoch += 1;
+        // the control stays locked until the ring is cleared,
+        // so that none of the frames decoded for the request are lost with the old ones
+        self.frames.clear();
+        self.next = None;
+    }
+
+    /// Wait for decoding and playback to finish,
+    /// returning the first error either of them ran into.
+    pub fn finish(&mut self) -> io::Result<()> {
+        // a paused output would never finish
+        self.set_paused(false);
+        let mut result = Ok(());
+        for thread in self.threads.drain(..) {
+            let outcome = thread
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "thread panicked")));
+            result = result.and(outcome);
+        }
+        match self.failed.lock().unwrap().take() {
+            Some(e) => result.and(Err(e)),
+            None => result,
+        }
+    }
+}
+
+impl AudioSource for Pipeline {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if let Some(e) = self.failed.lock().unwrap().take() {
+            return Err(e);
+        }
+        while let Some(item) = self.next.take().or_else(|| self.frames.recv()) {
+            // frames decoded before the last request are not seen, as they are not heard
+            if item.epoch < self.control.lock().unwrap().epoch {
+                continue;
+            }
+            self.track = item.progress.track;
+            self.start = item.start;
+            self.position = item.progress.position;
+            return Ok(Some(item.frame.clone()));
+        }
+        Ok(None)
+    }
+
+    fn is_ready(&mut self) -> bool {
+        if self.failed.lock().unwrap().is_some() {
+            return true;
+        }
+        while self.next.is_none() {
+            match self.frames.try_recv() {
+                Ok(item) => self.next = Some(item),
+                Err(TryRecvError::Empty) => return false,
+                // the end of the stream is handed out right away as well
+                Err(TryRecvError::Disconnected) => return true,
+            }
+        }
+        true
+    }
+
+    fn track(&self) -> u64 {
+        self.track
+    }
+
+    fn start(&self) -> Option<Duration> {
+        Some(self.start)
+    }
+
+    fn position(&self) -> Option<Duration> {
+        Some(self.position)
+    }
+}
+
+/// Hand the frames read from `output` to `sink` until there are no more,
+/// advancing `clock` and `progress` as they are played.
+fn play(
+    mut output: ring::Reader<Item>,
+    sink: &mut dyn Sink,
+    clock: &AudioClock,
+    control: &Mutex<Control>,
+    progress: &Mutex<Progress>,
+    paused: &(Mutex<bool>, Condvar),
+) -> io::Result<()> {
+    // moved on by the time spent paused, which the pacing below has to leave out
+    let mut started = Instant::now();
+    let mut format = None;
+    let mut epoch = 0;
+    while let Some(item) = output.recv() {
+        let resumed = wait(paused, sink);
+        if let Some(paused) = resumed {
+            started += paused;
+        }
+        let current = control.lock().unwrap().epoch;
+        if current != epoch {
+            // what the sink still holds belongs to the old position
+            sink.discard();
+            epoch = current;
+        }
+        if resumed.is_some() {
+            sink.set_paused(false);
+        }
+        if item.epoch < current {
+            continue;
+        }
+        let frame = &item.frame;
+        if format != Some((frame.sample_rate, frame.channels)) {
+            sink.start(frame.sample_rate, frame.channels)?;
+            format = Some((frame.sample_rate, frame.channels));
+        }
+        if !sink.is_realtime() {
+            // hold the frame back until everything before it would have been heard
+            let due = started + clock.now();
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        sink.write(&frame.data)?;
+        clock.advance(frame);
+        *progress.lock().unwrap() = Progress {
+            position: item.progress.position + frame.duration(),
+            ..item.progress
+        };
+    }
+    sink.finish()
+}
+
+/// Block while the pipeline is paused, pausing `sink` along with it.
+/// Returns how long it was paused for, if it was; the sink is left paused until
+/// the caller has dropped whatever was requested in the meantime.
+fn wait(paused: &(Mutex<bool>, Condvar), sink: &mut dyn Sink) -> Option<Duration> {
+    let (lock, changed) = paused;
+    let mut paused = lock.lock().unwrap();
+    if !*paused {
+        return None;
+    }
+    sink.set_paused(true);
+    let since = Instant::now();
+    while *paused {
+        paused = changed.wait(paused).unwrap();
+    }
+    Some(since.elapsed())
+}
+
+#[cfg(test)]
+struct Tone(usize);
+
+/// `AudioSource` that yields `Tone.0` frames of a constant value.
+#[cfg(test)]
+impl AudioSource for Tone {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if self.0 == 0 {
+            return Ok(None);
+        }
+        self.0 -= 1;
+        Ok(Some(Frame {
+            data: vec![self.0 as i16; 1152 * 2],
+            sample_rate: 44100,
+            channels: 2,
+        }))
+    }
+}
+
+#[cfg(test)]
+use std::sync::mpsc;
+
+/// What a `Gated` sink was asked to do.
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+enum Event {
+    Written,
+    Paused(bool),
+}
+
+/// `Sink` that reports what it does, and only lets go of a frame it is written
+/// once it is handed a permit, so that a test can step the output without waiting
+/// on the time of day. While it holds a frame, everything before it has been played.
+#[cfg(test)]
+struct Gated {
+    permits: mpsc::Receiver<()>,
+    events: mpsc::Sender<Event>,
+}
+
+#[cfg(test)]
+impl Sink for Gated {
+    fn start(&mut self, _sample_rate: u32, _channels: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, _samples: &[i16]) -> io::Result<()> {
+        let _ = self.events.send(Event::Written);
+        let _ = self.permits.recv();
+        Ok(())
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        let _ = self.events.send(Event::Paused(paused));
+    }
+
+    // paced by its permits rather than by the pipeline
+    fn is_realtime(&self) -> bool {
+        true
+    }
+}
+
+/// `Sink` whose device is gone.
+#[cfg(test)]
+struct Unplugged;
+
+#[cfg(test)]
+impl Sink for Unplugged {
+    fn start(&mut self, _sample_rate: u32, _channels: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, _samples: &[i16]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "unplugged"))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_headless() {
+    use crate::timing::Clock;
+
+    let mut pipeline = Pipeline::spawn(Tone(10), Box::new(sink::NullSink));
+    let clock = pipeline.clock();
+    let mut count = 0;
+    while pipeline.next_frame().unwrap().is_some() {
+        count += 1;
+    }
+    pipeline.finish().unwrap();
+    // the null sink is paced like a real device,
+    // so the visualizer keeps up and sees every frame
+    assert_eq!(count, 10);
+    assert_eq!(clock.now().as_micros(), 261_224);
+    // the last frame was heard after the nine before it
+    assert_eq!(pipeline.start().unwrap().as_micros(), 235_102);
+}
+#[test]
+fn test_wav_output() {
+    let path = std::env::temp_dir().join("kartina-test-pipeline.wav");
+    let sink = sink::WavSink::create(&path);
+    let mut pipeline = Pipeline::spawn(Tone(3), Box::new(sink));
+    pipeline.finish().unwrap();
+    let reader = hound::WavReader::open(&path).unwrap();
+    assert_eq!(reader.duration(), 1152 * 3);
+    std::fs::remove_file(path).unwrap();
+}
+#[test]
+fn test_pause() {
+    let (permit, permits) = mpsc::channel();
+    let (events, heard) = mpsc::channel();
+    let sink = Gated { permits, events };
+    let mut pipeline = Pipeline::spawn(Tone(10), Box::new(sink));
+    let clock = pipeline.clock();
+    permit.send(()).unwrap();
+    assert_eq!(heard.recv().unwrap(), Event::Written);
+    pipeline.set_paused(true);
+    assert!(pipeline.is_paused());
+    for _ in 1..10 {
+        permit.send(()).unwrap();
+    }
+    // at most the frame that was being written when the pause came goes out
+    let mut written = 1;
+    loop {
+        match heard.recv().unwrap() {
+            Event::Written => written += 1,
+            Event::Paused(true) => break,
+            event => panic!("{:?} before pausing", event),
+        }
+    }
+    assert!(written <= 2, "{} written", written);
+    assert_eq!(clock.now().as_micros(), [26_122, 52_244][written - 1]);
+    // the output is waiting for the pause to end
+    assert!(heard.try_recv().is_err());
+    pipeline.set_paused(false);
+    pipeline.finish().unwrap();
+    let rest: Vec<_> = heard.iter().collect();
+    assert_eq!(rest[0], Event::Paused(false));
+    assert_eq!(rest.len() - 1, 10 - written);
+    assert_eq!(clock.now().as_micros(), 261_224);
+    assert_eq!(pipeline.progress().position.as_micros(), 261_224);
+}
+#[test]
+fn test_seek() {
+    let track = std::path::PathBuf::from("./song/Can I Take A Picture With You-short.mp3");
+    let playlist = Playlist::new(vec![track]).unwrap();
+    let (permit, permits) = mpsc::channel();
+    let (events, heard) = mpsc::channel();
+    let sink = Gated { permits, events };
+    let mut pipeline = Pipeline::play(playlist, Box::new(sink)).unwrap();
+    // the output holds on to the first frame of the track
+    assert_eq!(heard.recv().unwrap(), Event::Written);
+    pipeline.seek(Duration::from_secs(3));
+    assert_eq!(pipeline.progress().position, Duration::from_secs(3));
+    // the frame it holds is played, and the next one it is given is from after the seek;
+    // once that one is played too and the output holds the one after it, progress is settled
+    permit.send(()).unwrap();
+    assert_eq!(heard.recv().unwrap(), Event::Written);
+    permit.send(()).unwrap();
+    assert_eq!(heard.recv().unwrap(), Event::Written);
+    let position = pipeline.progress().position;
+    assert!(
+        position > Duration::from_secs(3) && position < Duration::from_millis(3200),
+        "{:?}",
+        position
+    );
+    assert!(pipeline.progress().duration.is_some());
+    // the visualizer continues from the same place
+    assert!(pipeline.next_frame().unwrap().is_some());
+}
+#[test]
+fn test_missing_track() {
+    let missing = Playlist::load(std::path::Path::new("./song/missing.mp3")).unwrap();
+    assert!(Pipeline::play(missing, Box::new(sink::NullSink)).is_err());
+}
+#[test]
+fn test_output_error() {
+    use crate::timing::{FrameScheduler, Tick};
+
+    // the tone would go on for longer than the test
+    let pipeline = Pipeline::spawn(Tone(usize::MAX), Box::new(Unplugged));
+    let mut scheduler = FrameScheduler::new(pipeline);
+    // the clock stops with the output, but the visualizer is told why instead of waiting on it
+    let now = Duration::from_secs(3600);
+    let error = loop {
+        match scheduler.tick(now) {
+            Ok(Tick::End) => panic!("ended without the error"),
+            Ok(_) => {}
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    // decoding stopped with the output, and the error was only handed out once
+    scheduler.source_mut().finish().unwrap();
+}
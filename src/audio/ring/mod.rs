@@ -0,0 +1,317 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    collections::VecDeque,
+    sync::{mpsc::TryRecvError, Arc, Condvar, Mutex, MutexGuard},
+};
+
+/// A reader's place in the ring.
+/// `lossy` readers never hold the writer back; if they fall more than
+/// the ring's capacity behind, the oldest items are dropped from under them.
+struct Cursor {
+    next: u64,
+    lossy: bool,
+}
+
+struct Inner<T> {
+    items: VecDeque<Arc<T>>,
+    // sequence number of `items[0]`
+    head: u64,
+    capacity: usize,
+    readers: Vec<Option<Cursor>>,
+    closed: bool,
+}
+
+impl<T> Inner<T> {
+    fn tail(&self) -> u64 {
+        self.head + self.items.len() as u64
+    }
+
+    /// position of the slowest reader, counting only lossless readers when `lossy` is false.
+    fn slowest(&self, lossy: bool) -> Option<u64> {
+        self.readers
+            .iter()
+            .flatten()
+            .filter(|cursor| lossy || !cursor.lossy)
+            .map(|cursor| cursor.next)
+            .min()
+    }
+
+    /// drop every item that no reader needs anymore,
+    /// as well as anything past `capacity` that only lossy readers still need.
+    fn trim(&mut self) {
+        let all = self.slowest(true).unwrap_or_else(|| self.tail());
+        let lossless = self.slowest(false).unwrap_or_else(|| self.tail());
+        while !self.items.is_empty()
+            && (self.head < all || (self.items.len() > self.capacity && self.head < lossless))
+        {
+            self.items.pop_front();
+            self.head += 1;
+        }
+    }
+}
+
+struct Shared<T> {
+    inner: Mutex<Inner<T>>,
+    changed: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, Inner<T>> {
+        self.inner.lock().unwrap()
+    }
+}
+
+/// Create a ring buffer that holds up to `capacity` items
+/// for each of its lossless readers, returning its only `Writer`.
+///
+/// Every item pushed is handed to every reader, so that a single producer
+/// can feed several consumers that each move at their own pace.
+/// A reader sees the items pushed after it was added; once the writer is dropped
+/// and it has read all of them, `recv` returns `None`.
+pub fn channel<T>(capacity: usize) -> Writer<T> {
+    Writer {
+        shared: Arc::new(Shared {
+            inner: Mutex::new(Inner {
+                items: VecDeque::with_capacity(capacity),
+                head: 0,
+                capacity: capacity.max(1),
+                readers: Vec::new(),
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }),
+    }
+}
+
+/// Producing end of the ring. The ring is closed once the writer is dropped.
+pub struct Writer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Writer<T> {
+    /// Add a reader that will see every item pushed from now on.
+    pub fn reader(&self, lossy: bool) -> Reader<T> {
+        let mut inner = self.shared.lock();
+        let cursor = Cursor {
+            next: inner.tail(),
+            lossy,
+        };
+        let slot = match inner.readers.iter().position(Option::is_none) {
+            Some(slot) => {
+                inner.readers[slot] = Some(cursor);
+                slot
+            }
+            None => {
+                inner.readers.push(Some(cursor));
+                inner.readers.len() - 1
+            }
+        };
+        Reader {
+            shared: self.shared.clone(),
+            slot,
+        }
+    }
+
+    /// Push `item` into the ring, blocking while the slowest lossless reader
+    /// is a full `capacity` behind.
+    pub fn push(&self, item: T) {
+        let mut inner = self.shared.lock();
+        while let Some(slowest) = inner.slowest(false) {
+            if inner.tail() - slowest < inner.capacity as u64 {
+                break;
+            }
+            inner = self.shared.changed.wait(inner).unwrap();
+        }
+        inner.items.push_back(Arc::new(item));
+        inner.trim();
+        self.shared.changed.notify_all();
+    }
+}
+
+impl<T> Drop for Writer<T> {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Consuming end of the ring.
+pub struct Reader<T> {
+    shared: Arc<Shared<T>>,
+    slot: usize,
+}
+
+impl<T> Reader<T> {
+    /// Take the next item, blocking until one is available.
+    /// Returns `None` once the writer is gone and every item has been read.
+    /// A lossy reader that fell behind skips ahead to the oldest item still held.
+    pub fn recv(&mut self) -> Option<Arc<T>> {
+        let mut inner = self.shared.lock();
+        loop {
+            if let Some(item) = self.take(&mut inner) {
+                return Some(item);
+            }
+            if inner.closed {
+                return None;
+            }
+            inner = self.shared.changed.wait(inner).unwrap();
+        }
+    }
+
+    /// Take the next item if there is one, without blocking.
+    /// Fails with `Empty` while there is nothing new to read,
+    /// and with `Disconnected` once the writer is gone and every item has been read.
+    pub fn try_recv(&mut self) -> Result<Arc<T>, TryRecvError> {
+        let mut inner = self.shared.lock();
+        match self.take(&mut inner) {
+            Some(item) => Ok(item),
+            None if inner.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// move past the next item this reader has not seen, if the ring holds one.
+    fn take(&self, inner: &mut Inner<T>) -> Option<Arc<T>> {
+        let head = inner.head;
+        let tail = inner.tail();
+        let cursor = inner.readers[self.slot].as_mut().unwrap();
+        cursor.next = cursor.next.max(head);
+        if cursor.next == tail {
+            return None;
+        }
+        let index = (cursor.next - head) as usize;
+        cursor.next += 1;
+        let item = inner.items[index].clone();
+        inner.trim();
+        self.shared.changed.notify_all();
+        Some(item)
+    }
+
+    /// Drop every item the ring holds, for all of its readers,
+    /// so that each of them carries on with the next item pushed.
+    pub fn clear(&self) {
+        let mut inner = self.shared.lock();
+        inner.head = inner.tail();
+        inner.items.clear();
+        let head = inner.head;
+        for cursor in inner.readers.iter_mut().flatten() {
+            cursor.next = cursor.next.max(head);
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+impl<T> Drop for Reader<T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.lock();
+        inner.readers[self.slot] = None;
+        inner.trim();
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_broadcast() {
+    let writer = channel(8);
+    let mut first = writer.reader(false);
+    let mut second = writer.reader(false);
+    for i in 0..4 {
+        writer.push(i);
+    }
+    drop(writer);
+    for i in 0..4 {
+        assert_eq!(*first.recv().unwrap(), i);
+        assert_eq!(*second.recv().unwrap(), i);
+    }
+    assert!(first.recv().is_none());
+    assert!(second.recv().is_none());
+}
+#[test]
+fn test_late_reader() {
+    let writer = channel(4);
+    let mut early = writer.reader(false);
+    writer.push(0);
+    // a reader only sees what is pushed after it was added
+    let mut late = writer.reader(false);
+    writer.push(1);
+    drop(writer);
+    assert_eq!(*early.recv().unwrap(), 0);
+    assert_eq!(*early.recv().unwrap(), 1);
+    assert_eq!(*late.recv().unwrap(), 1);
+    assert!(late.recv().is_none());
+}
+#[test]
+fn test_lossy() {
+    let writer = channel(2);
+    let mut lossy = writer.reader(true);
+    // with no lossless readers the writer never blocks
+    for i in 0..5 {
+        writer.push(i);
+    }
+    assert_eq!(*lossy.recv().unwrap(), 3);
+    assert_eq!(*lossy.recv().unwrap(), 4);
+}
+#[test]
+fn test_backpressure() {
+    use std::{thread, time::Duration};
+
+    let writer = channel(2);
+    let mut reader = writer.reader(false);
+    let producer = thread::spawn(move || {
+        for i in 0..16 {
+            writer.push(i);
+        }
+    });
+    thread::sleep(Duration::from_millis(10));
+    // the writer must have waited for the reader instead of dropping anything
+    for i in 0..16 {
+        assert_eq!(*reader.recv().unwrap(), i);
+    }
+    assert!(reader.recv().is_none());
+    producer.join().unwrap();
+}
+#[test]
+fn test_try_recv() {
+    let writer = channel(2);
+    let mut reader = writer.reader(true);
+    assert_eq!(reader.try_recv(), Err(TryRecvError::Empty));
+    writer.push(0);
+    assert_eq!(*reader.try_recv().unwrap(), 0);
+    drop(writer);
+    assert_eq!(reader.try_recv(), Err(TryRecvError::Disconnected));
+}
+#[test]
+fn test_clear() {
+    let writer = channel(2);
+    let mut first = writer.reader(false);
+    let mut second = writer.reader(true);
+    writer.push(0);
+    writer.push(1);
+    second.clear();
+    // the writer is not held back by what was cleared
+    writer.push(2);
+    writer.push(3);
+    drop(writer);
+    assert_eq!(*first.recv().unwrap(), 2);
+    assert_eq!(*second.recv().unwrap(), 2);
+    assert_eq!(*first.recv().unwrap(), 3);
+    assert!(first.recv().is_none());
+}
@@ -0,0 +1,259 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    ffi::CStr,
+    fs::File,
+    io::{self, BufWriter},
+    os::raw::{c_int, c_long},
+    path::PathBuf,
+    ptr,
+};
+
+/// A `Sink` is where decoded audio ends up.
+/// The decode pipeline hands every frame to exactly one sink,
+/// which is free to play it, store it, or throw it away.
+pub trait Sink: Send {
+    /// Prepare the output for interleaved 16-bit samples with the given format.
+    /// Called before the first write, and again whenever the format changes.
+    fn start(&mut self, sample_rate: u32, channels: usize) -> io::Result<()>;
+
+    /// Write interleaved samples, blocking until the output has accepted them.
+    fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+
+    /// Flush anything still buffered once the stream has ended.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Throw away whatever has been written but not yet heard,
+    /// so that the stream can continue from somewhere else right away.
+    fn discard(&mut self) {}
+
+    /// Stop or resume playing what has been written.
+    /// Sinks that are not real-time have nothing to stop, as nothing is written while paused.
+    fn set_paused(&mut self, _paused: bool) {}
+
+    /// Whether `write` takes as long as the audio it was given lasts.
+    /// Sinks that are not real-time are paced by the pipeline,
+    /// so that the audio clock still runs at the speed of the music.
+    fn is_realtime(&self) -> bool {
+        false
+    }
+}
+
+/// `Sink` that discards everything written to it,
+/// so that the pipeline can run without any audio hardware.
+pub struct NullSink;
+
+impl Sink for NullSink {
+    fn start(&mut self, _sample_rate: u32, _channels: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, _samples: &[i16]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `Sink` that writes the stream to a 16-bit PCM WAV file.
+pub struct WavSink {
+    path: PathBuf,
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+}
+
+impl WavSink {
+    /// Create a sink that will write to `path` once the stream starts.
+    pub fn create<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            writer: None,
+        }
+    }
+}
+
+/// convert a `hound::Error` into an `io::Error`, keeping io errors as they are.
+pub(super) fn wav_error(e: hound::Error) -> io::Error {
+    match e {
+        hound::Error::IoError(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl Sink for WavSink {
+    fn start(&mut self, sample_rate: u32, channels: usize) -> io::Result<()> {
+        let spec = hound::WavSpec {
+            channels: channels as u16,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        match &self.writer {
+            // a WAV file has a single format, so the stream may not change it midway
+            Some(writer) if writer.spec() != spec => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the stream changed format, which a WAV file cannot hold",
+            )),
+            Some(_) => Ok(()),
+            None => {
+                self.writer = Some(hound::WavWriter::create(&self.path, spec).map_err(wav_error)?);
+                Ok(())
+            }
+        }
+    }
+
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "write before start"))?;
+        for sample in samples {
+            writer.write_sample(*sample).map_err(wav_error)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(writer) => writer.finalize().map_err(wav_error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `Sink` that plays the stream on the default audio device through out123,
+/// the output library that ships with mpg123.
+pub struct Out123Sink {
+    handle: *mut out123_sys::out123_handle,
+}
+
+// out123 handles are not tied to the thread that created them;
+// the pipeline only ever uses a sink from one thread at a time.
+unsafe impl Send for Out123Sink {}
+
+impl Out123Sink {
+    /// Open the default audio device.
+    pub fn open() -> io::Result<Self> {
+        let handle = unsafe { out123_sys::out123_new() };
+        if handle.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "failed to instantiate out123",
+            ));
+        }
+        let sink = Self { handle };
+        let result = unsafe { out123_sys::out123_open(handle, ptr::null(), ptr::null()) };
+        if result != out123_sys::OUT123_OK as c_int {
+            return Err(sink.error());
+        }
+        Ok(sink)
+    }
+
+    /// the last error reported by out123, as an `io::Error`.
+    fn error(&self) -> io::Error {
+        let message = unsafe { CStr::from_ptr(out123_sys::out123_strerror(self.handle)) };
+        io::Error::new(io::ErrorKind::Other, message.to_string_lossy().into_owned())
+    }
+}
+
+impl Sink for Out123Sink {
+    fn start(&mut self, sample_rate: u32, channels: usize) -> io::Result<()> {
+        let encoding = mpg123_sys::mpg123_enc_enum::MPG123_ENC_SIGNED_16.bits();
+        unsafe {
+            // let whatever was playing in the old format finish first
+            out123_sys::out123_drain(self.handle);
+            out123_sys::out123_stop(self.handle);
+            let result = out123_sys::out123_start(
+                self.handle,
+                sample_rate as c_long,
+                channels as c_int,
+                encoding,
+            );
+            if result != out123_sys::OUT123_OK as c_int {
+                return Err(self.error());
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes = std::mem::size_of_val(samples);
+        let played =
+            unsafe { out123_sys::out123_play(self.handle, samples.as_ptr() as *mut _, bytes) };
+        if played != bytes {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        unsafe { out123_sys::out123_drain(self.handle) };
+        Ok(())
+    }
+
+    fn discard(&mut self) {
+        unsafe { out123_sys::out123_drop(self.handle) };
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        unsafe {
+            if paused {
+                out123_sys::out123_pause(self.handle);
+            } else {
+                out123_sys::out123_continue(self.handle);
+            }
+        }
+    }
+
+    fn is_realtime(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for Out123Sink {
+    fn drop(&mut self) {
+        unsafe { out123_sys::out123_del(self.handle) };
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_wav_sink() {
+    let path = std::env::temp_dir().join("kartina-test-wav-sink.wav");
+    let mut sink = WavSink::create(&path);
+    sink.start(22050, 2).unwrap();
+    sink.write(&[1, -1, 2, -2]).unwrap();
+    sink.write(&[3, -3]).unwrap();
+    // the format may not change halfway through a file
+    assert!(sink.start(44100, 2).is_err());
+    sink.finish().unwrap();
+
+    let mut reader = hound::WavReader::open(&path).unwrap();
+    assert_eq!(reader.spec().sample_rate, 22050);
+    assert_eq!(reader.spec().channels, 2);
+    let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+    assert_eq!(samples, vec![1, -1, 2, -2, 3, -3]);
+    std::fs::remove_file(path).unwrap();
+}
+#[test]
+fn test_write_before_start() {
+    let mut sink = WavSink::create(std::env::temp_dir().join("kartina-test-unstarted.wav"));
+    assert!(sink.write(&[0]).is_err());
+    // nothing was started, so there is nothing to finish
+    assert!(sink.finish().is_ok());
+}
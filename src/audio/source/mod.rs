@@ -0,0 +1,893 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+
+/// number of samples per channel in the frames of formats that have no frames of their own,
+/// the same as an mp3 frame.
+pub(super) const FRAME_SAMPLES: usize = 1152;
+
+/// A `Frame` is a short run of decoded audio:
+/// interleaved 16-bit samples, along with how to play them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frame {
+    pub data: Vec<i16>,
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+impl Frame {
+    /// number of samples in each channel.
+    pub fn samples(&self) -> usize {
+        self.data.len() / self.channels.max(1)
+    }
+
+    /// how long the frame takes to play.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.samples() as f64 / self.sample_rate.max(1) as f64)
+    }
+}
+
+/// Anything that hands out decoded audio one `Frame` at a time.
+/// Returns `Ok(None)` once the stream has ended.
+pub trait AudioSource {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>>;
+
+    /// Whether `next_frame` would return without waiting.
+    /// Sources fed by a live device may have nothing yet, and are not waited on by the scheduler.
+    fn is_ready(&mut self) -> bool {
+        true
+    }
+
+    /// Number of the track the last frame belongs to, counting from 0 in the order they played,
+    /// so that a change of track can be noticed. Sources of a single track are always at 0.
+    fn track(&self) -> u64 {
+        0
+    }
+
+    /// When the last frame handed out is heard, on the clock of the output playing it.
+    /// Only sources that may skip frames need to say; for the others the scheduler
+    /// adds up the lengths of the frames it was handed.
+    fn start(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Where the last frame handed out starts within its track.
+    /// Sources that neither seek nor change tracks can leave this to the scheduler,
+    /// which counts from the start of the stream.
+    fn position(&self) -> Option<Duration> {
+        None
+    }
+
+    /// How long the current track lasts, when that is known without decoding all of it.
+    fn duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Move to `position` into the current track, returning the start of the frame
+    /// it landed on, which is the next one handed out.
+    fn seek(&mut self, _position: Duration) -> io::Result<Duration> {
+        Err(unsupported("seek"))
+    }
+
+    /// Skip to the next track.
+    fn next_track(&mut self) -> io::Result<()> {
+        Err(unsupported("skip tracks"))
+    }
+
+    /// Go back to the previous track.
+    fn previous_track(&mut self) -> io::Result<()> {
+        Err(unsupported("skip tracks"))
+    }
+}
+
+impl<S: AudioSource + ?Sized> AudioSource for Box<S> {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        (**self).next_frame()
+    }
+
+    fn is_ready(&mut self) -> bool {
+        (**self).is_ready()
+    }
+
+    fn track(&self) -> u64 {
+        (**self).track()
+    }
+
+    fn start(&self) -> Option<Duration> {
+        (**self).start()
+    }
+
+    fn position(&self) -> Option<Duration> {
+        (**self).position()
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        (**self).duration()
+    }
+
+    fn seek(&mut self, position: Duration) -> io::Result<Duration> {
+        (**self).seek(position)
+    }
+
+    fn next_track(&mut self) -> io::Result<()> {
+        (**self).next_track()
+    }
+
+    fn previous_track(&mut self) -> io::Result<()> {
+        (**self).previous_track()
+    }
+}
+
+/// the error of a source asked to do something it cannot, such as seeking through live audio.
+fn unsupported(action: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("this source cannot {}", action),
+    )
+}
+
+/// The audio formats kartina can decode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Mp3,
+    Wav,
+    Flac,
+    Ogg,
+}
+
+impl Format {
+    /// Recognize a format by the first bytes of a file.
+    ///
+    /// # Examples
+    /// ```
+    /// # use kartina::audio::source::Format;
+    /// assert!(Format::probe(b"fLaC\0\0\0\x22") == Some(Format::Flac));
+    /// ```
+    pub fn probe(header: &[u8]) -> Option<Self> {
+        match header {
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(Format::Wav),
+            [b'f', b'L', b'a', b'C', ..] => Some(Format::Flac),
+            [b'O', b'g', b'g', b'S', ..] => Some(Format::Ogg),
+            // an ID3 tag, or the sync word every mp3 frame starts with
+            [b'I', b'D', b'3', ..] => Some(Format::Mp3),
+            [0xff, second, ..] if second & 0xe0 == 0xe0 => Some(Format::Mp3),
+            _ => None,
+        }
+    }
+
+    /// Guess a format by the extension of `path`,
+    /// for files whose first bytes do not give them away.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "mp3" => Some(Format::Mp3),
+            "wav" => Some(Format::Wav),
+            "flac" => Some(Format::Flac),
+            "ogg" | "oga" => Some(Format::Ogg),
+            _ => None,
+        }
+    }
+}
+
+/// Open the audio file at `path`, picking the decoder by probing its first bytes.
+///
+/// # Examples
+/// ```
+/// # use kartina::audio::{source::open, AudioSource};
+/// # use std::path::Path;
+/// # fn main() -> std::io::Result<()> {
+/// let mut source = open(Path::new("./song/Can I Take A Picture With You-short.mp3"))?;
+/// let frame = source.next_frame()?.unwrap();
+/// assert!(frame.sample_rate == 44100);
+/// # Ok(())
+/// # }
+/// ```
+pub fn open(path: &Path) -> io::Result<Box<dyn AudioSource + Send>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut header = Vec::with_capacity(12);
+    file.by_ref().take(12).read_to_end(&mut header)?;
+    file.seek(SeekFrom::Start(0))?;
+    let format = Format::probe(&header)
+        .or_else(|| Format::from_extension(path))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an mp3, WAV, FLAC or Ogg Vorbis file",
+            )
+        })?;
+    Ok(match format {
+        Format::Mp3 => Box::new(Mp3Source::open(file)?),
+        Format::Wav => Box::new(WavSource::new(file)?),
+        Format::Flac => Box::new(FlacSource::new(file)?),
+        Format::Ogg => Box::new(OggSource::new(file)?),
+    })
+}
+
+/// scale a sample of `bits` bits to 16 bits.
+fn from_int(sample: i32, bits: u32) -> i16 {
+    if bits > 16 {
+        (sample >> (bits - 16)) as i16
+    } else {
+        (sample << (16 - bits)) as i16
+    }
+}
+
+/// scale a sample between -1.0 and 1.0 to 16 bits.
+fn from_float(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+/// wrap the error of one of the decoding libraries in an `io::Error`.
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// `AudioSource` for mp3, decoded by minimp3.
+///
+/// mp3 files hold no index of their frames. Seeking goes by the table of contents
+/// in the Xing header of VBR files, and otherwise by the average bitrate,
+/// which lands on the frame at the position for files of a constant bitrate.
+pub struct Mp3Source<R> {
+    // only ever empty while seeking, as minimp3 has to start over at the new position
+    decoder: Option<minimp3::Decoder<R>>,
+    // size of the file in bytes, and the sum of the bitrates (in kb/s) of the frames
+    // decoded so far along with their number, from which its duration is estimated
+    length: Option<u64>,
+    bitrates: (u64, u64),
+    // size of the tag in front of the first frame
+    tag: u64,
+    xing: Option<Xing>,
+}
+
+impl<R: Read> Mp3Source<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            decoder: Some(minimp3::Decoder::new(reader)),
+            length: None,
+            bitrates: (0, 0),
+            tag: 0,
+            xing: None,
+        }
+    }
+
+    /// Give the size of the file in bytes, so that its duration can be estimated.
+    pub fn with_length(mut self, length: u64) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    /// average number of bytes per second of the frames decoded so far.
+    fn byte_rate(&self) -> Option<f64> {
+        let (sum, count) = self.bitrates;
+        if count == 0 {
+            return None;
+        }
+        Some(sum as f64 / count as f64 * 1000.0 / 8.0)
+    }
+}
+
+impl<R: Read + Seek> Mp3Source<R> {
+    /// Start decoding the mp3 file `reader` reads from its start, looking ahead at its size,
+    /// its tag and the Xing header of a VBR file first, so that it can be seeked
+    /// and its duration is known.
+    pub fn open(mut reader: R) -> io::Result<Self> {
+        let length = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header = [0; 10];
+        let tag = match reader.read_exact(&mut header) {
+            Ok(()) => id3_size(&header),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e),
+        };
+        reader.seek(SeekFrom::Start(tag))?;
+        let mut frame = Vec::new();
+        reader.by_ref().take(XING_SIZE).read_to_end(&mut frame)?;
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            tag,
+            xing: Xing::parse(&frame),
+            ..Self::new(reader).with_length(length)
+        })
+    }
+}
+
+/// size in bytes of the ID3v2 tag at the start of an mp3 file, going by its first bytes,
+/// or 0 if it has none.
+fn id3_size(header: &[u8]) -> u64 {
+    match header {
+        // the size leaves out the 10 bytes of the tag's own header,
+        // and is stored in 7 bits of each of its bytes so as not to look like a sync word
+        [b'I', b'D', b'3', _, _, _, size @ ..] if size.len() >= 4 => {
+            10 + size[..4]
+                .iter()
+                .fold(0, |total, &byte| total << 7 | (byte & 0x7f) as u64)
+        }
+        _ => 0,
+    }
+}
+
+/// most bytes from the start of the first frame to the end of a Xing header.
+const XING_SIZE: u64 = 4 + 32 + 4 + 4 + 4 + 4 + 100;
+
+/// The Xing header a VBR mp3 file starts with, in the place of the audio of its first frame:
+/// how long the file lasts, and where in it each hundredth of its duration starts.
+#[derive(Clone, Debug, PartialEq)]
+struct Xing {
+    duration: Duration,
+    // bytes of audio, from the frame the header is in
+    bytes: Option<u64>,
+    // offset of each hundredth of the duration, in 256ths of `bytes`
+    toc: Option<Vec<u8>>,
+}
+
+impl Xing {
+    /// Read the header from the first bytes of the first frame of a file, if it is there
+    /// and says how many frames the file has. Only layer III files have one.
+    fn parse(frame: &[u8]) -> Option<Self> {
+        let header = frame.get(..4)?;
+        if header[0] != 0xff || header[1] & 0xe0 != 0xe0 || (header[1] >> 1) & 3 != 1 {
+            return None;
+        }
+        let mpeg1 = (header[1] >> 3) & 3 == 3;
+        let rate = match ((header[1] >> 3) & 3, (header[2] >> 2) & 3) {
+            (_, 3) | (1, _) => return None,
+            (version, index) => [44100, 48000, 32000][index as usize] >> (3 - version).min(2),
+        };
+        let mono = header[3] >> 6 == 3;
+        // the header comes after the side information, whose size depends on the frame
+        let side = match (mpeg1, mono) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        };
+        let mut fields = frame.get(4 + side..)?;
+        let mut take = |count: usize| {
+            let field = fields.get(..count)?;
+            fields = &fields[count..];
+            Some(field)
+        };
+        if !matches!(take(4)?, b"Xing" | b"Info") {
+            return None;
+        }
+        let number = |field: &[u8]| field.iter().fold(0, |n, &byte| n << 8 | byte as u64);
+        let flags = number(take(4)?);
+        if flags & 1 == 0 {
+            return None;
+        }
+        let samples = number(take(4)?) * if mpeg1 { 1152 } else { 576 };
+        let bytes = if flags & 2 != 0 {
+            Some(number(take(4)?))
+        } else {
+            None
+        };
+        let toc = if flags & 4 != 0 {
+            Some(take(100)?.to_vec())
+        } else {
+            None
+        };
+        Some(Self {
+            duration: Duration::from_secs_f64(samples as f64 / rate as f64),
+            bytes,
+            toc,
+        })
+    }
+
+    /// offset of `position` in the audio, from the start of the first frame,
+    /// going by the table of contents, if there is one.
+    fn offset(&self, position: Duration, bytes: u64) -> Option<u64> {
+        let toc = self.toc.as_ref()?;
+        let percent =
+            (position.as_secs_f64() / self.duration.as_secs_f64() * 100.0).clamp(0.0, 99.999);
+        let (index, within) = (percent as usize, percent.fract());
+        let from = toc[index] as f64;
+        let to = toc.get(index + 1).map_or(256.0, |&next| next as f64);
+        let fraction = (from + (to - from) * within) / 256.0;
+        Some((fraction * self.bytes.unwrap_or(bytes) as f64) as u64)
+    }
+}
+
+impl<R: Read + Seek> AudioSource for Mp3Source<R> {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let decoder = self.decoder.as_mut().unwrap();
+        loop {
+            return match decoder.next_frame() {
+                Ok(frame) => {
+                    if frame.bitrate > 0 {
+                        self.bitrates.0 += frame.bitrate as u64;
+                        self.bitrates.1 += 1;
+                    }
+                    Ok(Some(Frame {
+                        data: frame.data,
+                        sample_rate: frame.sample_rate.max(0) as u32,
+                        channels: frame.channels,
+                    }))
+                }
+                Err(minimp3::Error::Eof) => Ok(None),
+                // junk between frames, such as a tag, or a frame cut short, is not worth stopping for
+                Err(e @ minimp3::Error::SkippedData)
+                | Err(e @ minimp3::Error::InsufficientData) => {
+                    log::warn!("skipping part of the mp3: {}", e);
+                    continue;
+                }
+                Err(minimp3::Error::Io(e)) => Err(e),
+            };
+        }
+    }
+
+    /// given by the Xing header of VBR files, and otherwise estimated from the size of the file
+    /// and the average bitrate of the frames decoded so far,
+    /// which is exact for files of a constant bitrate, and closes in on it for the others.
+    fn duration(&self) -> Option<Duration> {
+        if let Some(xing) = &self.xing {
+            return Some(xing.duration);
+        }
+        let bytes = self.length?.saturating_sub(self.tag) as f64;
+        Some(Duration::from_secs_f64(bytes / self.byte_rate()?))
+    }
+
+    /// lands on the frame at the byte offset `position` works out to.
+    /// Without a Xing header, that takes the average bitrate of a frame decoded first.
+    fn seek(&mut self, position: Duration) -> io::Result<Duration> {
+        let bytes = self.length.unwrap_or(0).saturating_sub(self.tag);
+        let toc = self
+            .xing
+            .as_ref()
+            .and_then(|xing| xing.offset(position, bytes));
+        let offset = match (toc, self.byte_rate()) {
+            _ if position == Duration::from_secs(0) => 0,
+            (Some(offset), _) => self.tag + offset,
+            (None, Some(rate)) => self.tag + (position.as_secs_f64() * rate) as u64,
+            (None, None) => {
+                return Err(unsupported(
+                    "seek through an mp3 before knowing its bitrate",
+                ))
+            }
+        };
+        let (offset, position) = match (self.length, self.duration()) {
+            (Some(length), Some(duration)) if offset >= length => (length, duration),
+            _ => (offset, position),
+        };
+        // minimp3 keeps what it read ahead to itself, so it is started over at the offset
+        let mut reader = self.decoder.take().unwrap().into_inner();
+        let sought = reader.seek(SeekFrom::Start(offset));
+        self.decoder = Some(minimp3::Decoder::new(reader));
+        sought?;
+        Ok(position)
+    }
+}
+
+/// `AudioSource` for WAV files of any sample format, read by hound.
+pub struct WavSource<R> {
+    reader: hound::WavReader<R>,
+}
+
+impl<R: Read> WavSource<R> {
+    pub fn new(reader: R) -> io::Result<Self> {
+        Ok(Self {
+            reader: hound::WavReader::new(reader).map_err(super::sink::wav_error)?,
+        })
+    }
+}
+
+impl<R: Read + Seek> AudioSource for WavSource<R> {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let spec = self.reader.spec();
+        let count = FRAME_SAMPLES * spec.channels as usize;
+        let data = match spec.sample_format {
+            hound::SampleFormat::Float => self
+                .reader
+                .samples::<f32>()
+                .take(count)
+                .map(|sample| sample.map(from_float))
+                .collect::<Result<Vec<_>, _>>(),
+            hound::SampleFormat::Int => self
+                .reader
+                .samples::<i32>()
+                .take(count)
+                .map(|sample| sample.map(|s| from_int(s, spec.bits_per_sample as u32)))
+                .collect(),
+        }
+        .map_err(super::sink::wav_error)?;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Frame {
+            data,
+            sample_rate: spec.sample_rate,
+            channels: spec.channels as usize,
+        }))
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        let rate = self.reader.spec().sample_rate.max(1) as f64;
+        Some(Duration::from_secs_f64(
+            self.reader.duration() as f64 / rate,
+        ))
+    }
+
+    /// exact to the sample, as every sample of a WAV file takes as many bytes.
+    fn seek(&mut self, position: Duration) -> io::Result<Duration> {
+        let rate = self.reader.spec().sample_rate.max(1);
+        let sample = (position.as_secs_f64() * rate as f64) as u64;
+        let sample = sample.min(self.reader.duration() as u64) as u32;
+        self.reader.seek(sample)?;
+        Ok(Duration::from_secs_f64(sample as f64 / rate as f64))
+    }
+}
+
+/// `AudioSource` for FLAC, decoded by claxon one FLAC block at a time.
+///
+/// claxon cannot seek, so seeking skips over blocks on the way forward, and goes no further back
+/// than the block it stands on; the track has to be opened anew for that.
+pub struct FlacSource<R: Read> {
+    reader: claxon::FlacReader<R>,
+    // decoding buffer handed back to claxon for the next block
+    buffer: Vec<i32>,
+    // block a seek stopped on, handed out before the blocks after it
+    pending: Option<Frame>,
+    // sample the next frame handed out starts at, in each channel
+    next: u64,
+}
+
+impl<R: Read> FlacSource<R> {
+    pub fn new(reader: R) -> io::Result<Self> {
+        Ok(Self {
+            reader: claxon::FlacReader::new(reader).map_err(flac_error)?,
+            buffer: Vec::new(),
+            pending: None,
+            next: 0,
+        })
+    }
+
+    /// decode the next block of the stream, if there is one.
+    fn decode(&mut self) -> io::Result<Option<Frame>> {
+        let info = self.reader.streaminfo();
+        let buffer = std::mem::take(&mut self.buffer);
+        let block = match self.reader.blocks().read_next_or_eof(buffer) {
+            Ok(Some(block)) => block,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(flac_error(e)),
+        };
+        // claxon keeps each channel apart, so the samples are interleaved here
+        let mut data = Vec::with_capacity((block.duration() * block.channels()) as usize);
+        for i in 0..block.duration() {
+            for channel in 0..block.channels() {
+                data.push(from_int(block.sample(channel, i), info.bits_per_sample));
+            }
+        }
+        let channels = block.channels() as usize;
+        self.buffer = block.into_buffer();
+        Ok(Some(Frame {
+            data,
+            sample_rate: info.sample_rate,
+            channels,
+        }))
+    }
+}
+
+/// convert a `claxon::Error` into an `io::Error`, keeping io errors as they are.
+fn flac_error(e: claxon::Error) -> io::Error {
+    match e {
+        claxon::Error::IoError(e) => e,
+        e => invalid_data(e),
+    }
+}
+
+impl<R: Read> AudioSource for FlacSource<R> {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let frame = match self.pending.take() {
+            Some(frame) => Some(frame),
+            None => self.decode()?,
+        };
+        if let Some(frame) = &frame {
+            self.next += frame.samples() as u64;
+        }
+        Ok(frame)
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        let info = self.reader.streaminfo();
+        let rate = info.sample_rate.max(1) as f64;
+        Some(Duration::from_secs_f64(info.samples? as f64 / rate))
+    }
+
+    fn seek(&mut self, position: Duration) -> io::Result<Duration> {
+        let rate = self.reader.streaminfo().sample_rate.max(1);
+        let target = (position.as_secs_f64() * rate as f64) as u64;
+        if target < self.next {
+            return Err(unsupported("seek back through FLAC"));
+        }
+        while let Some(frame) = self.next_frame()? {
+            if self.next > target {
+                self.next -= frame.samples() as u64;
+                self.pending = Some(frame);
+                break;
+            }
+        }
+        Ok(Duration::from_secs_f64(self.next as f64 / rate as f64))
+    }
+}
+
+/// `AudioSource` for Ogg Vorbis, decoded by lewton one packet at a time.
+pub struct OggSource<R: Read + Seek> {
+    reader: lewton::inside_ogg::OggStreamReader<R>,
+    // packets a seek decoded from the one it stopped on, handed out before the packets after them
+    pending: VecDeque<Frame>,
+}
+
+impl<R: Read + Seek> OggSource<R> {
+    pub fn new(reader: R) -> io::Result<Self> {
+        Ok(Self {
+            reader: lewton::inside_ogg::OggStreamReader::new(reader).map_err(invalid_data)?,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// the frame of the interleaved samples of a packet.
+    fn frame(&self, data: Vec<i16>) -> Frame {
+        Frame {
+            data,
+            sample_rate: self.reader.ident_hdr.audio_sample_rate,
+            channels: self.reader.ident_hdr.audio_channels as usize,
+        }
+    }
+}
+
+impl<R: Read + Seek> AudioSource for OggSource<R> {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if let Some(frame) = self.pending.pop_front() {
+            return Ok(Some(frame));
+        }
+        loop {
+            match self.reader.read_dec_packet_itl().map_err(invalid_data)? {
+                // the first packet of a stream holds no audio
+                Some(data) if data.is_empty() => continue,
+                Some(data) => return Ok(Some(self.frame(data))),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// lewton seeks to the page the position is on, or one before it,
+    /// and the packets are then decoded up to the one the position is in.
+    fn seek(&mut self, position: Duration) -> io::Result<Duration> {
+        use lewton::{audio::AudioReadError, VorbisError};
+
+        let rate = self.reader.ident_hdr.audio_sample_rate.max(1) as f64;
+        let channels = self.reader.ident_hdr.audio_channels.max(1) as u64;
+        // positions are counted in samples of each channel, as the ends of pages are
+        let target = (position.as_secs_f64() * rate) as u64;
+        self.pending.clear();
+        // the first packet after a seek only starts the overlap and comes out empty,
+        // so the seek goes back by as many samples as a packet can finish
+        let longest = (1 << self.reader.ident_hdr.blocksize_1) / 2;
+        // the headers at the start of the stream are at 0 as well, and are skipped below
+        self.reader
+            .seek_absgp_pg(target.saturating_sub(longest).max(1))
+            .map_err(invalid_data)?;
+        // packets cannot be placed before the end of their page is known, so they wait here
+        let mut page = Vec::new();
+        let mut reached = target;
+        loop {
+            let data = match self.reader.read_dec_packet_itl() {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                Err(VorbisError::BadAudio(AudioReadError::AudioIsHeader)) => continue,
+                Err(e) => return Err(invalid_data(e)),
+            };
+            page.push(data);
+            let end = match self.reader.get_last_absgp() {
+                Some(end) => end,
+                None => continue,
+            };
+            let length: u64 = page.iter().map(|data| data.len() as u64 / channels).sum();
+            let mut start = end.saturating_sub(length);
+            for data in page.drain(..) {
+                let samples = data.len() as u64 / channels;
+                start += samples;
+                // the packets before the one the position is in are dropped
+                if data.is_empty() || self.pending.is_empty() && start <= target {
+                    continue;
+                }
+                if self.pending.is_empty() {
+                    reached = start - samples;
+                }
+                let frame = self.frame(data);
+                self.pending.push_back(frame);
+            }
+            if !self.pending.is_empty() {
+                break;
+            }
+            reached = end;
+        }
+        Ok(Duration::from_secs_f64(reached as f64 / rate))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_probe() {
+    assert_eq!(Format::probe(b"RIFF\x24\0\0\0WAVEfmt "), Some(Format::Wav));
+    assert_eq!(Format::probe(b"fLaC\0\0\0\x22"), Some(Format::Flac));
+    assert_eq!(Format::probe(b"OggS\0\x02"), Some(Format::Ogg));
+    assert_eq!(Format::probe(b"ID3\x04\0"), Some(Format::Mp3));
+    assert_eq!(Format::probe(&[0xff, 0xfb, 0x90, 0x64]), Some(Format::Mp3));
+    assert_eq!(Format::probe(b"RIFF\x24\0\0\0AVI "), None);
+    assert_eq!(Format::probe(b""), None);
+    assert_eq!(
+        Format::from_extension(Path::new("song.FLAC")),
+        Some(Format::Flac)
+    );
+    assert_eq!(Format::from_extension(Path::new("song")), None);
+}
+#[test]
+fn test_convert() {
+    assert_eq!(from_int(0x7f_ffff, 24), i16::MAX);
+    assert_eq!(from_int(-0x80_0000, 24), i16::MIN);
+    assert_eq!(from_int(-128, 8), i16::MIN);
+    assert_eq!(from_int(1234, 16), 1234);
+    assert_eq!(from_float(1.5), i16::MAX);
+    assert_eq!(from_float(-1.0), -i16::MAX);
+}
+#[test]
+fn test_mp3() {
+    let mut source = open(Path::new("./song/Can I Take A Picture With You-short.mp3")).unwrap();
+    let frame = source.next_frame().unwrap().unwrap();
+    assert_eq!((frame.sample_rate, frame.channels), (44100, 2));
+    assert_eq!(frame.samples(), 1152);
+    assert_eq!(frame.duration().as_micros(), 26_122);
+    // the estimate is within a frame or two of what the decoder makes of the whole song
+    let mut length = frame.duration();
+    while let Some(frame) = source.next_frame().unwrap() {
+        length += frame.duration();
+    }
+    let estimate = source.duration().unwrap();
+    assert!((estimate.as_secs_f64() - length.as_secs_f64()).abs() < 0.1);
+}
+#[test]
+fn test_mp3_seek() {
+    let mut source = open(Path::new("./song/Can I Take A Picture With You-short.mp3")).unwrap();
+    let first = source.next_frame().unwrap().unwrap();
+    // the song is VBR, and lands where its table of contents says, within a frame or so
+    let landed = source.seek(Duration::from_secs(30)).unwrap();
+    assert_eq!(landed, Duration::from_secs(30));
+    let mut rest = Duration::from_secs(0);
+    while let Some(frame) = source.next_frame().unwrap() {
+        rest += frame.duration();
+    }
+    let duration = source.duration().unwrap();
+    assert!(((duration - landed).as_secs_f64() - rest.as_secs_f64()).abs() < 0.3);
+    // the start is found exactly
+    source.seek(Duration::from_secs(0)).unwrap();
+    assert_eq!(source.next_frame().unwrap().unwrap(), first);
+}
+#[test]
+fn test_wav() {
+    let path = std::env::temp_dir().join("kartina-test-source.wav");
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 8000,
+        bits_per_sample: 24,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+    for i in 0..2000 {
+        writer.write_sample(i << 8).unwrap();
+    }
+    writer.finalize().unwrap();
+
+    // the extension is wrong on purpose; the file is probed
+    let probed = path.with_extension("mp3");
+    std::fs::rename(&path, &probed).unwrap();
+    let mut source = open(&probed).unwrap();
+    assert_eq!(source.duration(), Some(Duration::from_millis(250)));
+    // seeks land on the sample, and stop at the end of the track
+    assert_eq!(
+        source.seek(Duration::from_millis(100)).unwrap(),
+        Duration::from_millis(100)
+    );
+    assert_eq!(source.next_frame().unwrap().unwrap().data[0], 800);
+    assert_eq!(
+        source.seek(Duration::from_secs(1)).unwrap(),
+        Duration::from_millis(250)
+    );
+    assert!(source.next_frame().unwrap().is_none());
+    source.seek(Duration::from_secs(0)).unwrap();
+    let mut frames = Vec::new();
+    while let Some(frame) = source.next_frame().unwrap() {
+        frames.push(frame);
+    }
+    std::fs::remove_file(probed).unwrap();
+    // 2000 samples make one full frame and the rest
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].samples(), 1152);
+    assert_eq!(frames[1].samples(), 2000 - 1152);
+    assert_eq!((frames[0].sample_rate, frames[0].channels), (8000, 1));
+    assert_eq!(frames[1].data[0], 1152);
+}
+#[test]
+fn test_unknown() {
+    let path = std::env::temp_dir().join("kartina-test-source.txt");
+    std::fs::write(&path, "not music").unwrap();
+    let err = open(&path).err().unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_flac() {
+    // see tests/fixtures/tones.py for how the fixtures are made
+    let mut source = open(Path::new("./tests/fixtures/triangle.flac")).unwrap();
+    assert_eq!(source.duration(), Some(Duration::from_secs(1)));
+    let first = source.next_frame().unwrap().unwrap();
+    assert_eq!((first.sample_rate, first.channels), (8000, 2));
+    assert_eq!(first.samples(), 1024);
+    assert_eq!(first.data[..6], [0, 0, 32, -32, 64, -64]);
+    // seeks land on the start of the block the position is in
+    assert_eq!(
+        source.seek(Duration::from_millis(500)).unwrap(),
+        Duration::from_millis(384)
+    );
+    assert_eq!(
+        source.next_frame().unwrap().unwrap().data[..2],
+        [2304, -2304]
+    );
+    // and only go forward
+    let error = source.seek(Duration::from_millis(100)).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    let mut rest = 0;
+    while let Some(frame) = source.next_frame().unwrap() {
+        rest += frame.samples();
+    }
+    assert_eq!(rest, 8000 - 4 * 1024);
+}
+
+#[test]
+fn test_ogg() {
+    let mut source = open(Path::new("./tests/fixtures/tone.ogg")).unwrap();
+    let mut frames = Vec::new();
+    while let Some(frame) = source.next_frame().unwrap() {
+        frames.push(frame);
+    }
+    // two seconds in packets of 128 samples
+    assert_eq!(frames.len(), 125);
+    assert!(frames.iter().all(|frame| frame.samples() == 128));
+    assert_eq!((frames[0].sample_rate, frames[0].channels), (8000, 1));
+    // seeks land on the packet the position is in, wherever it is on its page
+    for &(position, packet) in &[(500, 31), (30, 1), (250, 15), (1000, 62)] {
+        let landed = source.seek(Duration::from_millis(position)).unwrap();
+        assert_eq!(landed, Duration::from_millis(packet * 16));
+        assert_eq!(
+            source.next_frame().unwrap().unwrap(),
+            frames[packet as usize]
+        );
+        assert_eq!(
+            source.next_frame().unwrap().unwrap(),
+            frames[packet as usize + 1]
+        );
+    }
+    source.seek(Duration::from_secs(0)).unwrap();
+    assert_eq!(source.next_frame().unwrap().unwrap(), frames[0]);
+}
@@ -0,0 +1,365 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use kartina::{state::color, Error, Preset};
+use std::{path::PathBuf, str::FromStr};
+use structopt::StructOpt;
+
+/// Where the decoded audio is sent.
+#[derive(Debug, PartialEq)]
+pub enum Output {
+    /// the default audio device
+    Device,
+    /// nowhere; the song plays silently
+    Null,
+    /// a WAV file at the given path
+    Wav(PathBuf),
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "device" => Ok(Output::Device),
+            "null" => Ok(Output::Null),
+            path if path.ends_with(".wav") => Ok(Output::Wav(path.into())),
+            _ => Err(format!(
+                "expected `device`, `null` or a path ending in `.wav`, found `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// parse a rotation axis written as three comma-separated numbers, e.g. `0,1,0`.
+fn parse_axis(s: &str) -> Result<[f32; 3], String> {
+    let error = || format!("expected three comma-separated numbers, found `{}`", s);
+    let axis = s
+        .split(',')
+        .map(|n| n.trim().parse::<f32>().map_err(|_| error()))
+        .collect::<Result<Vec<_>, _>>()?;
+    match axis[..] {
+        [x, y, z] if x != 0.0 || y != 0.0 || z != 0.0 => Ok([x, y, z]),
+        [_, _, _] => Err("the rotation axis cannot be zero".to_string()),
+        _ => Err(error()),
+    }
+}
+
+/// parse a number of images per second, which has to be at least 1.
+fn parse_fps(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err("there has to be at least one image per second".to_string()),
+        Ok(fps) => Ok(fps),
+        Err(_) => Err(format!("expected a whole number, found `{}`", s)),
+    }
+}
+
+/// Things kartina can do other than visualizing the track in a window.
+#[derive(Debug, PartialEq, StructOpt)]
+pub enum Command {
+    /// render a single frame of the track to an image, without opening a window
+    Snapshot {
+        /// index of the decoded audio frame to render
+        #[structopt(long, default_value = "0")]
+        frame: usize,
+
+        /// path of the image to write; its extension picks the format, e.g. `.png`
+        #[structopt(parse(from_os_str))]
+        out: PathBuf,
+    },
+    /// render the whole track to numbered PNGs and a WAV, for muxing into a video
+    Render {
+        /// number of images per second of music
+        #[structopt(long, default_value = "60", parse(try_from_str = parse_fps))]
+        fps: u32,
+
+        /// directory to write the images and `audio.wav` to; it is created if missing
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+}
+
+/// Command-line options for kartina.
+/// `track` is the only required argument, unless `--input` is given; it is used both
+/// to play the song and to decode the frames that color the sphere.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "kartina",
+    about = "Renders a sphere colored by a playing song."
+)]
+pub struct Opts {
+    /// path to the mp3, WAV, FLAC or Ogg Vorbis file to play and visualize,
+    /// or to a directory or `.m3u` playlist of them to play one after another
+    #[structopt(parse(from_os_str), required_unless = "input")]
+    pub track: Option<PathBuf>,
+    /// visualize what an input device hears instead of a track: a microphone, a line-in,
+    /// or a loopback device; `default` picks the system's default input
+    #[structopt(short, long, conflicts_with = "track")]
+    pub input: Option<String>,
+
+    /// width of the window in pixels
+    #[structopt(long, default_value = "800")]
+    pub width: u32,
+
+    /// height of the window in pixels
+    #[structopt(long, default_value = "600")]
+    pub height: u32,
+
+    /// open the window in borderless fullscreen mode
+    #[structopt(short, long)]
+    pub fullscreen: bool,
+
+    /// start the track, or the playlist, over once it has finished, instead of exiting
+    #[structopt(short = "l", long = "loop")]
+    pub repeat: bool,
+    /// play the tracks of a playlist in a random order
+    #[structopt(short, long)]
+    pub shuffle: bool,
+
+    /// name of the visual preset to use: `classic`, `night`, or one from the `--presets` file
+    #[structopt(short, long, default_value = "classic")]
+    pub preset: String,
+
+    /// TOML file of named presets, switched between with the number keys along with the built-in ones
+    #[structopt(long, parse(from_os_str))]
+    pub presets: Option<PathBuf>,
+
+    /// how the music is turned into colors, overriding the preset
+    #[structopt(short, long, possible_values = &color::BUILTIN)]
+    pub colors: Option<String>,
+
+    /// color every triangle on its own, rather than blending neighbouring triangles
+    #[structopt(long)]
+    pub flat: bool,
+
+    /// how far the music pushes the surface of the sphere out, relative to its radius; 0 keeps it round
+    #[structopt(long)]
+    pub displacement: Option<f32>,
+
+    /// number of horizontal slices of the sphere, overriding the preset
+    #[structopt(long)]
+    pub stacks: Option<u32>,
+
+    /// number of vertical slices of the sphere, overriding the preset
+    #[structopt(long)]
+    pub sectors: Option<u32>,
+
+    /// axis the sphere spins around, as `x,y,z`, overriding the preset
+    #[structopt(long, parse(try_from_str = parse_axis))]
+    pub rotation_axis: Option<[f32; 3]>,
+
+    /// degrees per second the sphere spins by, overriding the preset
+    #[structopt(long)]
+    pub rotation_speed: Option<f32>,
+
+    /// spin once every this many bars of the music, once its tempo is known
+    #[structopt(long)]
+    pub bars_per_turn: Option<f32>,
+
+    /// TOML file of camera keyframes to follow, instead of the mouse and keyboard
+    #[structopt(long, parse(from_os_str))]
+    pub camera: Option<PathBuf>,
+
+    /// where to send the audio: `device`, `null`, or a path ending in `.wav`;
+    /// a track is played on the device, while live input is not played back by default
+    #[structopt(short, long)]
+    pub output: Option<Output>,
+
+    /// what to do instead of opening a window; `--width` and `--height` size the image
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Opts {
+    /// Apply the settings given on the command line to `preset`.
+    /// Fails if that leaves the preset with values that make no sense, such as a sphere of a single stack.
+    pub fn customize(&self, preset: &mut Preset) -> Result<(), Error> {
+        preset.stacks = self.stacks.unwrap_or(preset.stacks);
+        preset.sectors = self.sectors.unwrap_or(preset.sectors);
+        preset.flat |= self.flat;
+        preset.displacement = self.displacement.unwrap_or(preset.displacement);
+        if let Some(colors) = &self.colors {
+            preset.colors = colors.clone();
+        }
+        preset.rotation.axis = self.rotation_axis.unwrap_or(preset.rotation.axis);
+        preset.rotation.speed = self.rotation_speed.unwrap_or(preset.rotation.speed);
+        preset.rotation.bars = self.bars_per_turn.or(preset.rotation.bars);
+        preset.validate().map_err(|e| {
+            Error::Usage(format!(
+                "the options given do not fit preset '{}': {}",
+                preset.name, e
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_defaults() {
+    let opts = Opts::from_iter_safe(&["kartina", "song.mp3"]).unwrap();
+    assert_eq!(opts.track, Some(PathBuf::from("song.mp3")));
+    assert_eq!(opts.input, None);
+    assert_eq!((opts.width, opts.height), (800, 600));
+    assert!(!opts.fullscreen);
+    assert!(!opts.repeat);
+    assert!(!opts.shuffle);
+    assert_eq!(opts.preset, "classic");
+    assert_eq!(opts.presets, None);
+    assert_eq!(opts.output, None);
+    assert_eq!(opts.command, None);
+    assert_eq!(opts.camera, None);
+    assert_eq!(opts.rotation_axis, None);
+    assert_eq!(opts.bars_per_turn, None);
+}
+#[test]
+fn test_flags() {
+    let opts = Opts::from_iter_safe(&[
+        "kartina",
+        "--width",
+        "1920",
+        "--height",
+        "1080",
+        "-f",
+        "--loop",
+        "-p",
+        "night",
+        "--stacks",
+        "64",
+        "--shuffle",
+        "--presets",
+        "looks.toml",
+        "music/",
+    ])
+    .unwrap();
+    assert_eq!((opts.width, opts.height), (1920, 1080));
+    assert!(opts.fullscreen);
+    assert!(opts.repeat);
+    assert!(opts.shuffle);
+    assert_eq!(opts.preset, "night");
+    assert_eq!(opts.presets, Some(PathBuf::from("looks.toml")));
+    assert_eq!((opts.stacks, opts.sectors), (Some(64), None));
+    let opts = Opts::from_iter_safe(&["kartina", "-c", "hue", "song.mp3"]).unwrap();
+    assert_eq!(opts.colors.as_deref(), Some("hue"));
+    assert!(!opts.flat);
+    assert!(
+        Opts::from_iter_safe(&["kartina", "--flat", "song.mp3"])
+            .unwrap()
+            .flat
+    );
+}
+#[test]
+fn test_rotation() {
+    let opts = Opts::from_iter_safe(&[
+        "kartina",
+        "--rotation-axis",
+        "0, 1,0",
+        "--rotation-speed",
+        "45",
+        "--bars-per-turn",
+        "4",
+        "song.mp3",
+    ])
+    .unwrap();
+    assert_eq!(opts.rotation_axis, Some([0.0, 1.0, 0.0]));
+    assert_eq!(opts.rotation_speed, Some(45.0));
+    assert_eq!(opts.bars_per_turn, Some(4.0));
+    assert!(parse_axis("0,0,0").is_err());
+    assert!(parse_axis("1,0").is_err());
+    assert!(parse_axis("x,y,z").is_err());
+}
+#[test]
+fn test_input() {
+    let opts = Opts::from_iter_safe(&["kartina", "--input", "default"]).unwrap();
+    assert_eq!(opts.input.as_deref(), Some("default"));
+    assert_eq!(opts.track, None);
+    let opts = Opts::from_iter_safe(&[
+        "kartina",
+        "-i",
+        "Monitor of Built-in Audio",
+        "-o",
+        "out.wav",
+    ]);
+    assert_eq!(opts.unwrap().output, Some(Output::Wav("out.wav".into())));
+    // a track and an input device cannot be visualized at once
+    assert!(Opts::from_iter_safe(&["kartina", "-i", "default", "song.mp3"]).is_err());
+}
+#[test]
+fn test_output() {
+    assert_eq!("null".parse(), Ok(Output::Null));
+    assert_eq!("out.wav".parse(), Ok(Output::Wav("out.wav".into())));
+    assert!("out.mp3".parse::<Output>().is_err());
+}
+#[test]
+fn test_snapshot() {
+    let opts = Opts::from_iter_safe(&[
+        "kartina", "--width", "640", "song.mp3", "snapshot", "--frame", "120", "out.png",
+    ])
+    .unwrap();
+    assert_eq!(opts.width, 640);
+    assert_eq!(
+        opts.command,
+        Some(Command::Snapshot {
+            frame: 120,
+            out: "out.png".into()
+        })
+    );
+    // the image path is required
+    assert!(Opts::from_iter_safe(&["kartina", "song.mp3", "snapshot"]).is_err());
+}
+#[test]
+fn test_render() {
+    let opts = Opts::from_iter_safe(&["kartina", "song.mp3", "render", "video"]).unwrap();
+    assert_eq!(
+        opts.command,
+        Some(Command::Render {
+            fps: 60,
+            dir: "video".into()
+        })
+    );
+    let opts = Opts::from_iter_safe(&["kartina", "song.mp3", "render", "--fps", "24", "video"]);
+    assert!(matches!(
+        opts.unwrap().command,
+        Some(Command::Render { fps: 24, .. })
+    ));
+    let opts = Opts::from_iter_safe(&["kartina", "song.mp3", "render", "--fps", "0", "video"]);
+    assert!(opts.is_err());
+}
+#[test]
+fn test_invalid() {
+    // the track is required
+    assert!(Opts::from_iter_safe(&["kartina"]).is_err());
+    assert!(Opts::from_iter_safe(&["kartina", "-c", "sepia", "song.mp3"]).is_err());
+    assert!(Opts::from_iter_safe(&["kartina", "--width", "wide", "song.mp3"]).is_err());
+}
+#[test]
+fn test_customize() {
+    let opts = Opts::from_iter_safe(&["kartina", "--stacks", "64", "song.mp3"]).unwrap();
+    let mut preset = Preset::default();
+    opts.customize(&mut preset).unwrap();
+    assert_eq!(preset.stacks, 64);
+    // values that are checked in a preset file are checked on the command line too
+    let opts = Opts::from_iter_safe(&["kartina", "--stacks", "1", "song.mp3"]).unwrap();
+    let error = opts.customize(&mut Preset::default()).err().unwrap();
+    assert!(matches!(error, Error::Usage(_)));
+    assert!(error
+        .to_string()
+        .starts_with("the options given do not fit preset 'classic': stacks 1"));
+    let opts = Opts::from_iter_safe(&["kartina", "--displacement", "NaN", "song.mp3"]).unwrap();
+    assert!(opts.customize(&mut Preset::default()).is_err());
+}
@@ -0,0 +1,140 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::{fmt, io, path::PathBuf};
+
+/// Everything that stops kartina from playing or rendering a song.
+/// Each error is displayed as a message meant for the person running kartina,
+/// saying what could not be done and why.
+#[derive(Debug)]
+pub enum Error {
+    /// the command line asks for something that cannot be done.
+    Usage(String),
+    /// the song, the playlist, or every track of it could not be opened.
+    Open { path: PathBuf, source: io::Error },
+    /// the song could not be decoded.
+    Decode(io::Error),
+    /// the preset file could not be read, or a preset in it is invalid.
+    Presets { path: PathBuf, source: io::Error },
+    /// there is no preset by the name asked for.
+    UnknownPreset { name: String, known: Vec<String> },
+    /// the camera path could not be read.
+    CameraPath { path: PathBuf, source: io::Error },
+    /// the audio device could not be opened.
+    AudioDevice(io::Error),
+    /// the input device could not be captured from.
+    Capture(io::Error),
+    /// decoding or playing the song failed partway through.
+    Playback(io::Error),
+    /// there is no graphics adapter to draw with.
+    NoAdapter,
+    /// the graphics adapter would not hand out a device.
+    Device(wgpu::RequestDeviceError),
+    /// the window could not be opened.
+    Window(winit::error::OsError),
+    /// a frame could not be drawn.
+    Render(wgpu::SwapChainError),
+    /// the images or the audio of an export could not be written to `path`.
+    Export { path: PathBuf, source: io::Error },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{}", message),
+            Error::Open { path, source } => {
+                write!(f, "cannot open '{}': {}", path.display(), source)
+            }
+            Error::Decode(e) => write!(f, "cannot decode the song: {}", e),
+            Error::Presets { path, source } => {
+                write!(f, "cannot load presets '{}': {}", path.display(), source)
+            }
+            Error::UnknownPreset { name, known } => write!(
+                f,
+                "there is no preset called '{}'; the presets are: {}",
+                name,
+                known.join(", ")
+            ),
+            Error::CameraPath { path, source } => write!(
+                f,
+                "cannot load camera path '{}': {}",
+                path.display(),
+                source
+            ),
+            Error::AudioDevice(e) => write!(f, "cannot open the audio device: {}", e),
+            Error::Capture(e) => write!(f, "cannot capture audio: {}", e),
+            Error::Playback(e) => write!(f, "playback failed: {}", e),
+            Error::NoAdapter => write!(
+                f,
+                "no graphics adapter is available; kartina needs a GPU with Vulkan, Metal or DirectX 12 drivers"
+            ),
+            Error::Device(e) => write!(f, "the graphics adapter cannot be used: {}", e),
+            Error::Window(e) => write!(f, "cannot open a window: {}", e),
+            Error::Render(e) => write!(f, "cannot draw the sphere: {}", e),
+            Error::Export { path, source } => {
+                write!(f, "cannot render '{}': {}", path.display(), source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Open { source, .. }
+            | Error::Presets { source, .. }
+            | Error::CameraPath { source, .. }
+            | Error::Export { source, .. } => Some(source),
+            Error::Decode(e) | Error::AudioDevice(e) | Error::Capture(e) | Error::Playback(e) => {
+                Some(e)
+            }
+            Error::Device(e) => Some(e),
+            Error::Window(e) => Some(e),
+            Error::Render(e) => Some(e),
+            Error::Usage(_) | Error::UnknownPreset { .. } | Error::NoAdapter => None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_messages() {
+    let missing = Error::Open {
+        path: PathBuf::from("song.mp3"),
+        source: io::Error::new(io::ErrorKind::NotFound, "No such file or directory"),
+    };
+    assert_eq!(
+        missing.to_string(),
+        "cannot open 'song.mp3': No such file or directory"
+    );
+    assert!(std::error::Error::source(&missing).is_some());
+    assert!(Error::NoAdapter
+        .to_string()
+        .starts_with("no graphics adapter"));
+    assert!(std::error::Error::source(&Error::NoAdapter).is_none());
+    let unknown = Error::UnknownPreset {
+        name: "disco".to_string(),
+        known: vec!["classic".to_string(), "night".to_string()],
+    };
+    assert_eq!(
+        unknown.to_string(),
+        "there is no preset called 'disco'; the presets are: classic, night"
+    );
+}
@@ -0,0 +1,346 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    analysis::{self, beat::Beat},
+    audio::{self, sink::WavSink, AudioSource, Frame, Sink},
+    error::{Error, Result},
+    preset::Preset,
+    state::{camera::path::CameraPath, State},
+    timing::{Clock, FrameScheduler, SimulatedClock, Tick},
+};
+use futures::executor::block_on;
+use std::{fs, io, path::Path, time::Duration};
+
+/// name of the audio track written next to the images of a video.
+const AUDIO: &str = "audio.wav";
+
+/// What a track looks like at one of its frames: the features of that frame,
+/// and the beats found on the way there, so the sphere can be rotated and pulsed
+/// exactly as it would have been had the song been played up to that point.
+struct Scene {
+    // start of each frame up to the chosen one, and the beat found in it, if any
+    frames: Vec<(Duration, Option<Beat>)>,
+    features: analysis::Features,
+}
+
+impl Scene {
+    /// Decode `source` up to and including frame number `index`.
+    fn decode<S: AudioSource>(source: &mut S, index: usize) -> io::Result<Self> {
+        let mut analyzer = analysis::Analyzer::default();
+        let mut tracker = analysis::beat::BeatTracker::new();
+        let mut frames = Vec::new();
+        let mut time = Duration::from_secs(0);
+        loop {
+            let frame = source.next_frame()?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("the track has only {} frames", frames.len()),
+                )
+            })?;
+            frames.push((time, tracker.process(&frame, time)));
+            if frames.len() > index {
+                return Ok(Self {
+                    frames,
+                    features: analyzer.analyze(&frame),
+                });
+            }
+            time += frame.duration();
+        }
+    }
+
+    /// Bring `state` to this scene, one update per decoded frame.
+    fn apply(&self, state: &mut State) {
+        let mut last = Duration::from_secs(0);
+        for (time, beat) in &self.frames {
+            if let Some(beat) = beat {
+                state.beat(beat);
+            }
+            state.seek(*time);
+            state.update(*time - last);
+            last = *time;
+        }
+        state.input(&self.features);
+    }
+}
+
+/// Create a `State` that renders offscreen, following `camera` if there is one.
+fn headless(width: u32, height: u32, preset: &Preset, camera: Option<CameraPath>) -> Result<State> {
+    let mut state = block_on(State::headless(width, height, preset))?;
+    if let Some(path) = camera {
+        state.set_camera_path(path);
+    }
+    Ok(state)
+}
+
+/// Render frame number `index` of the audio file at `track` to the image at `out`,
+/// without opening a window or playing the song.
+/// The format of the image follows the extension of `out`, e.g. `.png`.
+/// When a `camera` path is given, the camera is placed where the path has it at that frame.
+///
+/// # Examples
+/// ```no_run
+/// # use kartina::{export::snapshot, Preset};
+/// # use std::path::Path;
+/// # fn main() -> kartina::Result<()> {
+/// let track = Path::new("./song/Can I Take A Picture With You-short.mp3");
+/// snapshot(track, 100, Path::new("frame.png"), 800, 600, &Preset::default(), None)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn snapshot(
+    track: &Path,
+    index: usize,
+    out: &Path,
+    width: u32,
+    height: u32,
+    preset: &Preset,
+    camera: Option<CameraPath>,
+) -> Result<()> {
+    let mut source = audio::source::open(track).map_err(|source| Error::Open {
+        path: track.to_path_buf(),
+        source,
+    })?;
+    let scene = Scene::decode(&mut source, index).map_err(Error::Decode)?;
+    let mut state = headless(width, height, preset, camera)?;
+    scene.apply(&mut state);
+    state.render().map_err(Error::Render)?;
+    save(&state, out).map_err(|source| Error::Export {
+        path: out.to_path_buf(),
+        source,
+    })
+}
+
+/// Read the image `state` rendered last back from the GPU, and save it to `out`.
+fn save(state: &State, out: &Path) -> io::Result<()> {
+    let image = block_on(state.capture()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "the rendered image could not be read back",
+        )
+    })?;
+    image
+        .save(out)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+/// `AudioSource` that hands every frame it decodes to a `Sink` on the way through,
+/// so the audio of an export is written from the very frames that were drawn.
+struct Recorder<S> {
+    source: S,
+    sink: Box<dyn Sink>,
+    format: Option<(u32, usize)>,
+    // set once writing to the sink failed, so that the error is not taken for one of decoding
+    failed: bool,
+}
+
+impl<S: AudioSource> Recorder<S> {
+    fn new(source: S, sink: Box<dyn Sink>) -> Self {
+        Self {
+            source,
+            sink,
+            format: None,
+            failed: false,
+        }
+    }
+
+    /// write `frame` to the sink, starting it anew when the format changes.
+    fn record(&mut self, frame: &Frame) -> io::Result<()> {
+        if self.format != Some((frame.sample_rate, frame.channels)) {
+            self.sink.start(frame.sample_rate, frame.channels)?;
+            self.format = Some((frame.sample_rate, frame.channels));
+        }
+        self.sink.write(&frame.data)
+    }
+
+    /// flush the sink once the source has run dry.
+    fn finish(&mut self) -> io::Result<()> {
+        self.sink.finish()
+    }
+}
+
+impl<S: AudioSource> AudioSource for Recorder<S> {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let frame = match self.source.next_frame()? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        if let Err(e) = self.record(&frame) {
+            self.failed = true;
+            return Err(e);
+        }
+        Ok(Some(frame))
+    }
+}
+
+/// Render the whole audio file at `track` as a video of `fps` frames per second,
+/// writing numbered PNGs and the song as `audio.wav` into `dir`. `fps` cannot be 0.
+/// Returns the number of images written.
+///
+/// The decoder is walked by a `SimulatedClock` that moves `1 / fps` seconds per image,
+/// so the result only depends on the track and never on how long rendering takes.
+/// The same clock moves the camera along its path, if one is given.
+/// A track that cannot be opened or decoded fails with `Error::Open` or `Error::Decode`,
+/// and anything that cannot be written into `dir` with `Error::Export`.
+/// The images and audio can then be muxed with other tools, e.g.
+/// `ffmpeg -framerate 60 -i frame-%06d.png -i audio.wav video.mp4`.
+///
+/// # Examples
+/// ```no_run
+/// # use kartina::{export::render, Preset};
+/// # use std::path::Path;
+/// # fn main() -> kartina::Result<()> {
+/// let track = Path::new("./song/Can I Take A Picture With You-short.mp3");
+/// let count = render(track, Path::new("video"), 60, 1920, 1080, &Preset::default(), None)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn render(
+    track: &Path,
+    dir: &Path,
+    fps: u32,
+    width: u32,
+    height: u32,
+    preset: &Preset,
+    camera: Option<CameraPath>,
+) -> Result<u64> {
+    let decoder = audio::source::open(track).map_err(|source| Error::Open {
+        path: track.to_path_buf(),
+        source,
+    })?;
+    let export = |source| Error::Export {
+        path: dir.to_path_buf(),
+        source,
+    };
+    fs::create_dir_all(dir).map_err(export)?;
+    let recorder = Recorder::new(decoder, Box::new(WavSink::create(dir.join(AUDIO))));
+    let mut state = headless(width, height, preset, camera)?;
+    let mut analyzer = analysis::Analyzer::default();
+    let mut tracker = analysis::beat::BeatTracker::new();
+    let mut scheduler = FrameScheduler::new(recorder);
+    let clock = SimulatedClock::new();
+    let step = Duration::from_secs(1) / fps;
+
+    let mut count = 0;
+    loop {
+        let tick = scheduler.tick(clock.now()).map_err(|source| {
+            // the recorder writes the audio into `dir` as it decodes the track
+            if scheduler.source_mut().failed {
+                export(source)
+            } else {
+                Error::Decode(source)
+            }
+        })?;
+        match tick {
+            Tick::Present(due) => {
+                if let Some(last) = due.last() {
+                    state.input(&analyzer.analyze(&last.frame));
+                }
+                // every frame is listened to for beats, even those too short to be drawn
+                for due in &due {
+                    if let Some(beat) = tracker.process(&due.frame, due.position) {
+                        state.beat(&beat);
+                    }
+                }
+            }
+            Tick::Repeat => {}
+            Tick::End => break,
+        }
+        state.seek(clock.now());
+        state.update(step);
+        state.render().map_err(Error::Render)?;
+        save(&state, &dir.join(format!("frame-{:06}.png", count))).map_err(export)?;
+        count += 1;
+        clock.advance(step);
+    }
+    scheduler.source_mut().finish().map_err(export)?;
+    log::info!(
+        "rendered {:?} as {} images: {} frames dropped, {} repeated",
+        scheduler.position(),
+        count,
+        scheduler.dropped(),
+        scheduler.repeated()
+    );
+    Ok(count)
+}
+
+#[cfg(test)]
+const TRACK: &str = "./song/Can I Take A Picture With You-short.mp3";
+
+#[cfg(test)]
+#[test]
+fn test_decode() {
+    let mut track = audio::source::open(Path::new(TRACK)).unwrap();
+    let scene = Scene::decode(&mut track, 200).unwrap();
+    assert_eq!(scene.frames.len(), 201);
+    // frames follow one another without gaps
+    let (start, _) = scene.frames[200];
+    // (200 frames of 1152 samples at 44.1kHz)
+    assert_eq!(start.as_micros(), 5_224_489);
+    assert!(scene.features.loudness() > 0.0);
+}
+#[test]
+fn test_past_the_end() {
+    let mut track = audio::source::open(Path::new(TRACK)).unwrap();
+    let err = Scene::decode(&mut track, usize::MAX).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+#[test]
+fn test_recorder() {
+    let path = std::env::temp_dir().join("kartina-test-recorder.wav");
+    let track = audio::source::open(Path::new(TRACK)).unwrap();
+    let sink = Box::new(WavSink::create(&path));
+    let mut recorder = Recorder::new(track, sink);
+    let mut samples = 0;
+    for _ in 0..10 {
+        let frame = recorder.next_frame().unwrap().unwrap();
+        samples += frame.data.len() / frame.channels;
+    }
+    recorder.finish().unwrap();
+    let reader = hound::WavReader::open(&path).unwrap();
+    assert_eq!(reader.duration() as usize, samples);
+    std::fs::remove_file(path).unwrap();
+}
+#[test]
+fn test_recorder_failed() {
+    // the track decodes fine, but the sink cannot create its file
+    let path = std::env::temp_dir().join("kartina-missing-dir").join(AUDIO);
+    let track = audio::source::open(Path::new(TRACK)).unwrap();
+    let mut recorder = Recorder::new(track, Box::new(WavSink::create(path)));
+    assert!(recorder.next_frame().is_err());
+    assert!(recorder.failed);
+}
+#[test]
+fn test_missing_track() {
+    let out = std::env::temp_dir().join("kartina-test-missing.png");
+    let err = snapshot(
+        Path::new("./song/missing.mp3"),
+        0,
+        &out,
+        8,
+        8,
+        &Preset::default(),
+        None,
+    )
+    .err()
+    .unwrap();
+    assert!(matches!(err, Error::Open { .. }), "{}", err);
+    assert!(err
+        .to_string()
+        .starts_with("cannot open './song/missing.mp3'"));
+}
@@ -0,0 +1,61 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Kartina renders a sphere that moves and changes color with music.
+//!
+//! The pieces it is built from can be used on their own:
+//! - `audio` decodes tracks, captures input devices and plays them back in step with a clock,
+//! - `analysis` turns decoded frames into the features and beats that drive the sphere,
+//! - `state` builds the sphere (`Vertex`, `SphereBuilder`), places the `Camera`,
+//!   and draws everything into a window, an offscreen texture or any other wgpu surface,
+//! - `export` renders tracks to images without opening a window.
+//!
+//! # Examples
+//! ```no_run
+//! # use futures::executor::block_on;
+//! # use kartina::{audio::Frame, Analyzer, Error, Preset, State};
+//! # fn main() -> kartina::Result<()> {
+//! # let frame = Frame { data: vec![0; 2304], sample_rate: 44100, channels: 2 };
+//! let mut state = block_on(State::headless(800, 600, &Preset::default()))?;
+//! let mut analyzer = Analyzer::default();
+//! state.input(&analyzer.analyze(&frame));
+//! state.update(frame.duration());
+//! state.render().map_err(Error::Render)?;
+//! let image = block_on(state.capture());
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod analysis;
+pub mod audio;
+pub mod error;
+pub mod export;
+pub mod playlist;
+pub mod preset;
+pub mod state;
+pub mod timing;
+
+pub use analysis::{Analyzer, Features};
+pub use error::{Error, Result};
+pub use preset::Preset;
+pub use state::{
+    camera::Camera,
+    mesh::Mesh,
+    vertex::{SphereBuilder, Vertex},
+    State,
+};
@@ -16,63 +16,215 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use minimp3::{Decoder, Error};
-use std::{fs::File, thread, time::Duration};
+use kartina::{
+    analysis, audio, audio::AudioSource, export, playlist, preset, state, timing, timing::Clock,
+    Error,
+};
+use std::{process, time::Duration};
+use structopt::StructOpt;
 use winit::{
+    dpi::PhysicalSize,
     event::*,
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Fullscreen, WindowBuilder},
 };
 
-mod state;
+mod cli;
+
+/// how far the arrow keys seek back or forth.
+const SEEK_STEP: Duration = Duration::from_secs(10);
 
-/// This is the `main` method. Two threads are spawned:
-/// One to play the song in the background,
-/// and another to open the window and handle the rendering.
+/// This is the `main` method. The song is decoded once by the audio `Pipeline`,
+/// which spawns a thread to decode the track and another to play it.
+/// A directory or playlist is played track after track, without tearing down the window.
+/// With `--input`, the pipeline captures from an input device instead of decoding a file.
+/// The main thread opens the window and draws whichever decoded frame
+/// matches what is currently being heard.
 fn main() {
-    thread::spawn(|| {
-        // child thread
-        play::play("./song/Can I Take A Picture With You-short.mp3").unwrap();
-        thread::sleep(Duration::from_millis(1));
+    if let Err(e) = run(cli::Opts::from_args()) {
+        fail(e);
+    }
+}
+
+/// Tell the user what stopped kartina, and exit.
+fn fail(e: Error) -> ! {
+    eprintln!("kartina: {}", e);
+    process::exit(1);
+}
+
+/// Play, or render, what `opts` asks for.
+/// Once the window is open this never returns; errors from then on end the process through `fail`.
+fn run(opts: cli::Opts) -> Result<(), Error> {
+    env_logger::init();
+    // the built-in presets come first, then those of the file,
+    // which replace any built-in preset of the same name
+    let mut presets: Vec<_> = preset::Preset::BUILTIN
+        .iter()
+        .filter_map(|name| preset::Preset::builtin(name))
+        .collect();
+    if let Some(path) = &opts.presets {
+        let loaded = preset::Preset::load(path).map_err(|source| Error::Presets {
+            path: path.clone(),
+            source,
+        })?;
+        for preset in loaded {
+            match presets.iter_mut().find(|known| known.name == preset.name) {
+                Some(known) => *known = preset,
+                None => presets.push(preset),
+            }
+        }
+    }
+    // the command line overrides every preset, so that it holds when switching between them
+    for preset in &mut presets {
+        opts.customize(preset)?;
+    }
+    let selected = presets
+        .iter()
+        .position(|preset| preset.name == opts.preset)
+        .ok_or_else(|| Error::UnknownPreset {
+            name: opts.preset.clone(),
+            known: presets.iter().map(|preset| preset.name.clone()).collect(),
+        })?;
+    let preset = &presets[selected];
+    let camera = match &opts.camera {
+        Some(path) => Some(
+            state::camera::path::CameraPath::load(path).map_err(|source| Error::CameraPath {
+                path: path.clone(),
+                source,
+            })?,
+        ),
+        None => None,
+    };
+
+    // the subcommands render without a window, then exit
+    if let Some(command) = &opts.command {
+        let track = opts.track.as_ref().ok_or_else(|| {
+            Error::Usage("only a track can be rendered, not an input device".to_string())
+        })?;
+        return match command {
+            cli::Command::Snapshot { frame, out } => {
+                export::snapshot(track, *frame, out, opts.width, opts.height, preset, camera)
+            }
+            cli::Command::Render { fps, dir } => {
+                export::render(track, dir, *fps, opts.width, opts.height, preset, camera)
+                    .map(|_| ())
+            }
+        };
+    }
+    // what is captured live is already being heard, so it is not played back unless asked
+    let output = opts.output.as_ref().unwrap_or(match opts.input {
+        Some(_) => &cli::Output::Null,
+        None => &cli::Output::Device,
     });
+    let sink: Box<dyn audio::Sink> = match output {
+        cli::Output::Device => {
+            Box::new(audio::sink::Out123Sink::open().map_err(Error::AudioDevice)?)
+        }
+        cli::Output::Null => Box::new(audio::sink::NullSink),
+        cli::Output::Wav(path) => Box::new(audio::sink::WavSink::create(path)),
+    };
+    let pipeline = match (&opts.input, &opts.track) {
+        (Some(device), _) => {
+            let device = Some(device.as_str()).filter(|device| *device != "default");
+            audio::Pipeline::listen(device, sink).map_err(Error::Capture)?
+        }
+        // structopt requires one or the other
+        (None, track) => {
+            let track = track.as_ref().unwrap();
+            let open = |source| Error::Open {
+                path: track.clone(),
+                source,
+            };
+            let mut playlist = playlist::Playlist::load(track).map_err(open)?;
+            playlist.repeat = opts.repeat;
+            if opts.shuffle {
+                playlist.shuffle(&mut rand::thread_rng());
+            }
+            audio::Pipeline::play(playlist, sink).map_err(open)?
+        }
+    };
+    let clock = pipeline.clock();
+    let mut analyzer = analysis::Analyzer::default();
+    let mut tracker = analysis::beat::BeatTracker::new();
+    let mut scheduler = timing::FrameScheduler::new(pipeline);
+
     // parent thread
-    env_logger::init();
     let event_loop = EventLoop::new();
-    let window = WindowBuilder::new().build(&event_loop).unwrap();
-    let mut decoder = Decoder::new(File::open("./song/Can I Take A Picture With You.mp3").unwrap());
+    let mut builder = WindowBuilder::new()
+        .with_title("kartina")
+        .with_inner_size(PhysicalSize::new(opts.width, opts.height));
+    if opts.fullscreen {
+        builder = builder.with_fullscreen(Some(Fullscreen::Borderless(None)));
+    }
+    let window = builder.build(&event_loop).map_err(Error::Window)?;
+    // the sphere moves with the music, so it is animated by the audio clock
+    let mut last_update = clock.now();
+    let mut playing = 0;
 
     use futures::executor::block_on;
 
     // main cannot be asynchronous,
     // so we need to block thread to create state
-    let mut state: state::State = block_on(state::State::new(&window));
+    let mut state: state::State = block_on(state::State::new(&window, preset))?;
+    if let Some(path) = camera {
+        state.set_camera_path(path);
+    }
 
     event_loop.run(move |event, _, control_flow| {
-        match decoder.next_frame() {
-            Ok(frame) => {
-                state.input(&frame);
-            }
-            // The song is over, let's close the window
-            Err(Error::Eof) => *control_flow = ControlFlow::Exit,
-            Err(e) => panic!("{:?}", e),
-        }
         match event {
             Event::WindowEvent {
                 ref event,
                 window_id,
             } if window_id == window.id() => {
+                // the camera gets the first look at every event
+                if state.handle_event(event) {
+                    return;
+                }
                 match event {
                     WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                    // this match pattern yields a cargo clippy error, though making the suggested
-                    // fix does not work.
-                    WindowEvent::KeyboardInput { input, .. } => match input {
-                        KeyboardInput {
-                            state: ElementState::Pressed,
-                            virtual_keycode: Some(VirtualKeyCode::Escape),
-                            ..
-                        } => *control_flow = ControlFlow::Exit,
-                        _ => {}
-                    },
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::Escape),
+                                ..
+                            },
+                        ..
+                    } => *control_flow = ControlFlow::Exit,
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                        ..
+                    } => {
+                        if let Some(number) = number(*key) {
+                            if let Some(preset) = presets.get(number - 1) {
+                                log::info!("switching to preset '{}'", preset.name);
+                                window.set_title(&format!("kartina: {}", preset.name));
+                                state.set_preset(preset);
+                            }
+                            return;
+                        }
+                        let pipeline = scheduler.source_mut();
+                        let position = pipeline.progress().position;
+                        match key {
+                            VirtualKeyCode::Space => pipeline.set_paused(!pipeline.is_paused()),
+                            VirtualKeyCode::Left => {
+                                pipeline.seek(position.saturating_sub(SEEK_STEP))
+                            }
+                            VirtualKeyCode::Right => pipeline.seek(position + SEEK_STEP),
+                            VirtualKeyCode::N => pipeline.next_track(),
+                            VirtualKeyCode::P => pipeline.previous_track(),
+                            _ => return,
+                        }
+                        // whatever was decoded ahead is gone, the next frame is due right away
+                        if *key != VirtualKeyCode::Space {
+                            scheduler.restart(clock.now());
+                        }
+                    }
                     WindowEvent::Resized(physical_size) => {
                         state.resize(*physical_size);
                     }
@@ -84,15 +236,61 @@ fn main() {
                 }
             }
             Event::RedrawRequested(_) => {
-                state.update();
+                match scheduler.tick(clock.now()) {
+                    Ok(timing::Tick::Present(due)) => {
+                        // the visuals carry on into the next track, blending into it
+                        let track = scheduler.source_mut().track();
+                        if track != playing {
+                            playing = track;
+                            state.crossfade();
+                        }
+                        if let Some(last) = due.last() {
+                            state.input(&analyzer.analyze(&last.frame));
+                        }
+                        // only the latest frame is drawn, but the beats are found in all of them
+                        for due in &due {
+                            if let Some(beat) = tracker.process(&due.frame, due.position) {
+                                log::debug!("beat at {:?}, {:?} bpm", beat.time, beat.bpm);
+                                state.beat(&beat);
+                            }
+                        }
+                    }
+                    Ok(timing::Tick::Repeat) => {}
+                    // The song, or the playlist, is over, let's close the window.
+                    // When looping, the pipeline starts it over and it never ends.
+                    Ok(timing::Tick::End) => {
+                        if let Err(e) = scheduler.source_mut().finish() {
+                            fail(Error::Playback(e));
+                        }
+                        log::info!(
+                            "played {:?}: {} frames dropped, {} repeated",
+                            scheduler.position(),
+                            scheduler.dropped(),
+                            scheduler.repeated()
+                        );
+                        *control_flow = ControlFlow::Exit
+                    }
+                    Err(e) => fail(Error::Playback(e)),
+                }
+                let pipeline = scheduler.source_mut();
+                let progress = pipeline.progress();
+                let fraction = progress
+                    .duration
+                    .map(|duration| progress.position.as_secs_f32() / duration.as_secs_f32());
+                state.progress(fraction, pipeline.is_paused());
+                // the camera path follows the song, through seeks and changes of track
+                state.seek(progress.position);
+                let now = clock.now();
+                state.update(now.saturating_sub(last_update));
+                last_update = now;
                 match state.render() {
                     Ok(_) => {}
                     // Recreate the swap_chain if lost
                     Err(wgpu::SwapChainError::Lost) => state.resize(state.size),
                     // The system is out of memory, we should probably quit
-                    Err(wgpu::SwapChainError::OutOfMemory) => *control_flow = ControlFlow::Exit,
+                    Err(e @ wgpu::SwapChainError::OutOfMemory) => fail(Error::Render(e)),
                     // All other errors (Outdated, Timeout) should be resolved by the next frame
-                    Err(e) => eprintln!("{:?}", e),
+                    Err(e) => log::warn!("{}", e),
                 }
             }
             Event::MainEventsCleared => {
@@ -104,3 +302,10 @@ fn main() {
         }
     });
 }
+
+/// the number on `key`, from 1 to 9, which picks the preset at that place in the list.
+fn number(key: VirtualKeyCode) -> Option<usize> {
+    use VirtualKeyCode::*;
+    let keys = [Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    keys.iter().position(|k| *k == key).map(|i| i + 1)
+}
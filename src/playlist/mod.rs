@@ -0,0 +1,439 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::audio::{
+    source::{self, Format},
+    AudioSource, Frame,
+};
+use rand::seq::SliceRandom;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// A `Playlist` is a list of tracks played one after another,
+/// in the order they were given or shuffled, once or over and over.
+///
+/// # Examples
+/// ```
+/// # use kartina::playlist::Playlist;
+/// # use std::path::Path;
+/// # fn main() -> std::io::Result<()> {
+/// let mut playlist = Playlist::load(Path::new("./song"))?;
+/// playlist.repeat = true;
+/// playlist.shuffle(&mut rand::thread_rng());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Playlist {
+    tracks: Vec<PathBuf>,
+    // indices into `tracks`, in the order they are played
+    order: Vec<usize>,
+    position: usize,
+    // set once a track of the playlist is played, which shuffling then leaves playing
+    playing: bool,
+    /// start over from the first track once the last one has ended.
+    pub repeat: bool,
+}
+
+impl Playlist {
+    /// Create a playlist of `tracks`, in that order.
+    /// Returns `None` if there are no tracks.
+    pub fn new(tracks: Vec<PathBuf>) -> Option<Self> {
+        if tracks.is_empty() {
+            return None;
+        }
+        Some(Self {
+            order: (0..tracks.len()).collect(),
+            tracks,
+            position: 0,
+            playing: false,
+            repeat: false,
+        })
+    }
+
+    /// Load the tracks at `path`, which is either an audio file,
+    /// a directory whose audio files are played in the order of their names,
+    /// or an `.m3u` playlist.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let tracks = if path.is_dir() {
+            directory(path)?
+        } else if is_m3u(path) {
+            m3u(path)?
+        } else {
+            vec![path.to_path_buf()]
+        };
+        Self::new(tracks).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("there are no tracks in '{}'", path.display()),
+            )
+        })
+    }
+
+    /// Put the tracks in a random order.
+    /// Once the playlist is playing, the new order starts with the track that is playing.
+    pub fn shuffle<R: rand::Rng + ?Sized>(&mut self, rng: &mut R) {
+        let current = self.order[self.position];
+        self.order.shuffle(rng);
+        if self.playing {
+            let position = self.order.iter().position(|&i| i == current).unwrap();
+            self.order.swap(0, position);
+        }
+        self.position = 0;
+    }
+
+    /// number of tracks.
+    fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// path of the track that is playing.
+    pub fn current(&self) -> &Path {
+        &self.tracks[self.order[self.position]]
+    }
+
+    /// Move on to the track after the current one, as when it has ended.
+    /// Returns `false` at the end of a playlist that does not repeat.
+    pub fn advance(&mut self) -> bool {
+        self.playing = true;
+        if self.position + 1 < self.order.len() {
+            self.position += 1;
+        } else if self.repeat {
+            self.position = 0;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Go back to the track before the current one.
+    /// The first track starts over, unless the playlist repeats and wraps around to the last.
+    pub fn previous(&mut self) {
+        self.playing = true;
+        if self.position > 0 {
+            self.position -= 1;
+        } else if self.repeat {
+            self.position = self.order.len() - 1;
+        }
+    }
+}
+
+/// whether `path` is named like an m3u playlist.
+fn is_m3u(path: &Path) -> bool {
+    let extension = path.extension().and_then(|e| e.to_str());
+    matches!(
+        extension.map(str::to_ascii_lowercase).as_deref(),
+        Some("m3u") | Some("m3u8")
+    )
+}
+
+/// the audio files directly inside `dir`, sorted by name.
+fn directory(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut tracks = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && Format::from_extension(&path).is_some() {
+            tracks.push(path);
+        }
+    }
+    tracks.sort();
+    Ok(tracks)
+}
+
+/// the tracks listed in the m3u playlist at `path`.
+/// Lines starting with `#` are comments or extended m3u information, and are skipped;
+/// relative paths are relative to the playlist itself.
+fn m3u(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line))
+        .collect())
+}
+
+/// `AudioSource` that plays the tracks of a `Playlist` without gaps,
+/// opening the next track as soon as the last one has run out of frames.
+///
+/// Tracks that cannot be opened are skipped with a warning.
+/// Seeking asks the track's decoder to seek first; when it cannot, e.g. a FLAC track
+/// going backwards, the track is opened again and its decoder asked once more,
+/// and only if that fails too is the track decoded from the start up to the new position.
+pub struct PlaylistSource {
+    playlist: Playlist,
+    source: Box<dyn AudioSource + Send>,
+    // frame a seek stopped on, handed out before anything else
+    pending: Option<Frame>,
+    // set once the track was skipped past the end of the playlist
+    ended: bool,
+    track: u64,
+}
+
+impl PlaylistSource {
+    /// Start playing the current track of `playlist`.
+    /// An error opening it is returned, so that a playlist that cannot start is reported.
+    pub fn new(mut playlist: Playlist) -> io::Result<Self> {
+        playlist.playing = true;
+        let source = source::open(playlist.current())?;
+        log::info!("playing '{}'", playlist.current().display());
+        Ok(Self {
+            playlist,
+            source,
+            pending: None,
+            ended: false,
+            track: 0,
+        })
+    }
+
+    /// Open the current track of the playlist as the next track,
+    /// moving past those that cannot be opened.
+    /// Returns `false` if the end of the playlist was reached on the way.
+    fn open(&mut self) -> io::Result<bool> {
+        self.pending = None;
+        for _ in 0..self.playlist.len() {
+            let path = self.playlist.current();
+            match source::open(path) {
+                Ok(source) => {
+                    log::info!("playing '{}'", path.display());
+                    self.source = source;
+                    self.track += 1;
+                    return Ok(true);
+                }
+                Err(e) => {
+                    log::warn!("skipping '{}': {}", path.display(), e);
+                    if !self.playlist.advance() {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "none of the tracks in the playlist can be played",
+        ))
+    }
+}
+
+impl AudioSource for PlaylistSource {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if let Some(frame) = self.pending.take() {
+            return Ok(Some(frame));
+        }
+        while !self.ended {
+            if let Some(frame) = self.source.next_frame()? {
+                return Ok(Some(frame));
+            }
+            self.ended = !self.playlist.advance() || !self.open()?;
+        }
+        Ok(None)
+    }
+
+    fn track(&self) -> u64 {
+        self.track
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.source.duration()
+    }
+
+    /// Sources that cannot go back from where they are start over from the top of the track,
+    /// and those that cannot seek at all are decoded up to `position`.
+    fn seek(&mut self, position: Duration) -> io::Result<Duration> {
+        self.pending = None;
+        match self.source.seek(position) {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+            landed => return landed,
+        }
+        self.source = source::open(self.playlist.current())?;
+        match self.source.seek(position) {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+            landed => return landed,
+        }
+        let mut start = Duration::from_secs(0);
+        while let Some(frame) = self.source.next_frame()? {
+            if start + frame.duration() > position {
+                self.pending = Some(frame);
+                break;
+            }
+            start += frame.duration();
+        }
+        // past the end of the track, the next one starts right away
+        Ok(start)
+    }
+
+    /// On the last track of a playlist that does not repeat, this ends the playlist.
+    fn next_track(&mut self) -> io::Result<()> {
+        self.ended = !self.playlist.advance() || !self.open()?;
+        Ok(())
+    }
+
+    fn previous_track(&mut self) -> io::Result<()> {
+        self.playlist.previous();
+        self.ended = !self.open()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn tracks(names: &[&str]) -> Vec<PathBuf> {
+    names.iter().map(PathBuf::from).collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_order() {
+    let mut playlist = Playlist::new(tracks(&["a.mp3", "b.mp3", "c.mp3"])).unwrap();
+    assert_eq!(playlist.current(), Path::new("a.mp3"));
+    assert!(playlist.advance());
+    assert!(playlist.advance());
+    assert_eq!(playlist.current(), Path::new("c.mp3"));
+    // the end of a playlist that does not repeat
+    assert!(!playlist.advance());
+    assert_eq!(playlist.current(), Path::new("c.mp3"));
+    playlist.repeat = true;
+    assert!(playlist.advance());
+    assert_eq!(playlist.current(), Path::new("a.mp3"));
+    assert!(Playlist::new(Vec::new()).is_none());
+}
+#[test]
+fn test_previous() {
+    let mut playlist = Playlist::new(tracks(&["a.mp3", "b.mp3"])).unwrap();
+    playlist.advance();
+    playlist.previous();
+    assert_eq!(playlist.current(), Path::new("a.mp3"));
+    // the first track starts over
+    playlist.previous();
+    assert_eq!(playlist.current(), Path::new("a.mp3"));
+    playlist.repeat = true;
+    playlist.previous();
+    assert_eq!(playlist.current(), Path::new("b.mp3"));
+}
+#[test]
+fn test_shuffle() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let names: Vec<String> = (0..20).map(|i| format!("{}.mp3", i)).collect();
+    let mut playlist = Playlist::new(names.iter().map(PathBuf::from).collect()).unwrap();
+    playlist.advance();
+    playlist.shuffle(&mut StdRng::seed_from_u64(7));
+    // the track that is playing stays first, and every track is still there once
+    assert_eq!(playlist.current(), Path::new("1.mp3"));
+    let mut order = playlist.order.clone();
+    assert_ne!(order, (0..20).collect::<Vec<_>>());
+    order.sort_unstable();
+    assert_eq!(order, (0..20).collect::<Vec<_>>());
+}
+#[test]
+fn test_shuffle_before_playing() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let names: Vec<String> = (0..20).map(|i| format!("{}.mp3", i)).collect();
+    let mut playlist = Playlist::new(names.iter().map(PathBuf::from).collect()).unwrap();
+    playlist.shuffle(&mut StdRng::seed_from_u64(7));
+    // nothing is playing yet, so the first track is shuffled along with the others
+    assert_ne!(playlist.current(), Path::new("0.mp3"));
+    assert_eq!(playlist.position, 0);
+}
+#[test]
+fn test_m3u() {
+    let dir = std::env::temp_dir().join("kartina-test-playlist");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("set.m3u");
+    fs::write(
+        &path,
+        "#EXTM3U\n#EXTINF:300,Andrey Pushkarev\nfirst.mp3\n\n/music/second.flac\n",
+    )
+    .unwrap();
+    let playlist = Playlist::load(&path).unwrap();
+    assert_eq!(
+        playlist.tracks,
+        vec![dir.join("first.mp3"), "/music/second.flac".into()]
+    );
+    fs::remove_file(&path).unwrap();
+    // the playlist file is gone, so the directory holds no tracks
+    assert!(Playlist::load(&dir).is_err());
+}
+#[test]
+fn test_directory() {
+    let playlist = Playlist::load(Path::new("./song")).unwrap();
+    assert!(playlist.len() > 0);
+    assert!(playlist
+        .tracks
+        .iter()
+        .all(|track| Format::from_extension(track).is_some()));
+    let single = Path::new("./song/Can I Take A Picture With You-short.mp3");
+    assert_eq!(Playlist::load(single).unwrap().current(), single);
+}
+#[test]
+fn test_gapless() {
+    let track = PathBuf::from("./song/Can I Take A Picture With You-short.mp3");
+    let playlist = Playlist::new(vec![track.clone(), "./song/missing.mp3".into(), track]).unwrap();
+    let mut single = source::open(playlist.current()).unwrap();
+    let mut frames = 0;
+    while single.next_frame().unwrap().is_some() {
+        frames += 1;
+    }
+    let mut source = PlaylistSource::new(playlist).unwrap();
+    let mut played = 0;
+    let mut tracks = Vec::new();
+    while source.next_frame().unwrap().is_some() {
+        played += 1;
+        tracks.push(source.track());
+    }
+    // the missing track is skipped, and the other two follow one another
+    assert_eq!(played, 2 * frames);
+    assert_eq!(tracks[frames - 1], 0);
+    assert_eq!(tracks[frames], 1);
+}
+#[test]
+fn test_skip() {
+    let track = PathBuf::from("./song/Can I Take A Picture With You-short.mp3");
+    let playlist = Playlist::new(vec![track.clone(), track]).unwrap();
+    let mut source = PlaylistSource::new(playlist).unwrap();
+    source.next_frame().unwrap();
+    source.next_track().unwrap();
+    assert_eq!(source.track(), 1);
+    source.previous_track().unwrap();
+    assert_eq!(source.track(), 2);
+    assert!(source.next_frame().unwrap().is_some());
+    // skipping past the last track ends the playlist
+    source.next_track().unwrap();
+    source.next_track().unwrap();
+    assert!(source.next_frame().unwrap().is_none());
+}
+#[test]
+fn test_seek() {
+    let track = PathBuf::from("./song/Can I Take A Picture With You-short.mp3");
+    let mut source = PlaylistSource::new(Playlist::new(vec![track]).unwrap()).unwrap();
+    let first = source.next_frame().unwrap().unwrap();
+    let frame = first.duration();
+    let start = source.seek(Duration::from_secs(2)).unwrap();
+    assert!(start <= Duration::from_secs(2) && start + frame > Duration::from_secs(2));
+    assert_ne!(source.next_frame().unwrap().unwrap(), first);
+    // back to the start
+    assert_eq!(
+        source.seek(Duration::from_secs(0)).unwrap(),
+        Duration::from_secs(0)
+    );
+    assert_eq!(source.next_frame().unwrap().unwrap(), first);
+    assert_eq!(source.track(), 0);
+}
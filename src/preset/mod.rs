@@ -0,0 +1,395 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::state::{color, rotation::Rotation, vertex::SphereBuilder};
+use serde::Deserialize;
+use std::{fs, io, path::Path};
+
+/// most slices the sphere can be cut into either way, which is already far more than can be seen.
+const MAX_SLICES: u32 = 1024;
+
+/// Where the camera starts out, and how wide it sees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct View {
+    pub eye: [f32; 3],
+    pub target: [f32; 3],
+    /// vertical field of view, in degrees.
+    pub fovy: f32,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        Self {
+            eye: [0.0, 1.0, 2.0],
+            target: [0.0, 0.0, 0.0],
+            fovy: 45.0,
+        }
+    }
+}
+
+/// A `Preset` bundles together the visual settings used by `State`,
+/// so that the look of the visualizer can be chosen by name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    pub clear_color: wgpu::Color,
+    /// number of horizontal and vertical slices the sphere is made of.
+    pub stacks: u32,
+    pub sectors: u32,
+    /// color every triangle on its own, instead of blending colors across neighbouring triangles.
+    pub flat: bool,
+    /// how far the loudest band pushes the surface of the sphere out, relative to its radius.
+    pub displacement: f32,
+    /// strength of the highlights the light leaves on the sphere, 0.0 for a matte sphere.
+    pub specular: f32,
+    /// name of the `ColorMapper` that turns the music into colors.
+    pub colors: String,
+    /// axis and speed the sphere spins at.
+    pub rotation: Rotation,
+    /// where the camera starts out.
+    pub camera: View,
+}
+
+/// a preset as it is written in a file; whatever is left out is taken from `classic`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetEntry {
+    name: String,
+    clear_color: Option<Vec<f64>>,
+    stacks: Option<u32>,
+    sectors: Option<u32>,
+    flat: Option<bool>,
+    displacement: Option<f32>,
+    specular: Option<f32>,
+    colors: Option<String>,
+    rotation: Option<RotationEntry>,
+    camera: Option<ViewEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RotationEntry {
+    axis: Option<[f32; 3]>,
+    speed: Option<f32>,
+    bars: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ViewEntry {
+    eye: Option<[f32; 3]>,
+    target: Option<[f32; 3]>,
+    fovy: Option<f32>,
+}
+
+/// a file of presets.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetFile {
+    preset: Vec<PresetEntry>,
+}
+
+impl Preset {
+    /// names of the presets that ship with kartina.
+    pub const BUILTIN: [&'static str; 2] = ["classic", "night"];
+
+    /// Look up one of the built-in presets by `name`.
+    /// Returns `None` if there is no preset with that name.
+    ///
+    /// # Examples
+    /// ```
+    /// # use kartina::Preset;
+    /// let preset = Preset::builtin("night").unwrap();
+    /// assert!(preset.clear_color == wgpu::Color::BLACK);
+    /// ```
+    pub fn builtin(name: &str) -> Option<Self> {
+        let clear_color = match name {
+            "classic" => wgpu::Color::WHITE,
+            "night" => wgpu::Color::BLACK,
+            _ => return None,
+        };
+        Some(Self {
+            name: name.to_string(),
+            clear_color,
+            stacks: 18,
+            sectors: 36,
+            flat: false,
+            displacement: 0.35,
+            specular: 0.5,
+            colors: "bands".to_string(),
+            rotation: Rotation::default(),
+            camera: View::default(),
+        })
+    }
+
+    /// Read the presets in the TOML file at `path`.
+    pub fn load(path: &Path) -> io::Result<Vec<Self>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Read presets from TOML `source`, in the order they are written.
+    /// Fails if there are none, if two share a name, or if a value is out of range.
+    ///
+    /// Presets are written as a list, leaving out whatever should be as in `classic`:
+    /// ```toml
+    /// [[preset]]
+    /// name = "ember"
+    /// clear_color = [0.05, 0.0, 0.0] # red, green, blue and optionally alpha, from 0 to 1
+    /// stacks = 24
+    /// sectors = 48
+    /// flat = true
+    /// displacement = 0.5
+    /// specular = 0.2
+    /// colors = "energy"
+    /// rotation = { axis = [0.0, 1.0, 0.0], speed = 60.0, bars = 4.0 }
+    /// camera = { eye = [0.0, 0.5, 3.0], target = [0.0, 0.0, 0.0], fovy = 40.0 }
+    /// ```
+    pub fn parse(source: &str) -> io::Result<Vec<Self>> {
+        let file: PresetFile = toml::from_str(source).map_err(invalid)?;
+        if file.preset.is_empty() {
+            return Err(invalid("a preset file needs at least one preset"));
+        }
+        let mut presets: Vec<Self> = Vec::new();
+        for entry in file.preset {
+            let preset = Self::from_entry(entry);
+            if presets.iter().any(|other| other.name == preset.name) {
+                return Err(invalid(format!(
+                    "there is more than one preset called '{}'",
+                    preset.name
+                )));
+            }
+            preset
+                .validate()
+                .map_err(|e| invalid(format!("preset '{}': {}", preset.name, e)))?;
+            presets.push(preset);
+        }
+        Ok(presets)
+    }
+
+    /// the preset `entry` describes, on top of `classic`.
+    /// A clear color of the wrong length is kept as NaN, for `validate` to reject.
+    fn from_entry(entry: PresetEntry) -> Self {
+        let mut preset = Self {
+            name: entry.name,
+            ..Self::default()
+        };
+        if let Some(color) = entry.clear_color {
+            preset.clear_color = match color[..] {
+                [r, g, b] => wgpu::Color { r, g, b, a: 1.0 },
+                [r, g, b, a] => wgpu::Color { r, g, b, a },
+                _ => wgpu::Color {
+                    r: f64::NAN,
+                    g: f64::NAN,
+                    b: f64::NAN,
+                    a: f64::NAN,
+                },
+            };
+        }
+        preset.stacks = entry.stacks.unwrap_or(preset.stacks);
+        preset.sectors = entry.sectors.unwrap_or(preset.sectors);
+        preset.flat = entry.flat.unwrap_or(preset.flat);
+        preset.displacement = entry.displacement.unwrap_or(preset.displacement);
+        preset.specular = entry.specular.unwrap_or(preset.specular);
+        preset.colors = entry.colors.unwrap_or(preset.colors);
+        if let Some(rotation) = entry.rotation {
+            preset.rotation.axis = rotation.axis.unwrap_or(preset.rotation.axis);
+            preset.rotation.speed = rotation.speed.unwrap_or(preset.rotation.speed);
+            preset.rotation.bars = rotation.bars.or(preset.rotation.bars);
+        }
+        if let Some(camera) = entry.camera {
+            preset.camera.eye = camera.eye.unwrap_or(preset.camera.eye);
+            preset.camera.target = camera.target.unwrap_or(preset.camera.target);
+            preset.camera.fovy = camera.fovy.unwrap_or(preset.camera.fovy);
+        }
+        preset
+    }
+
+    /// Check that every setting is one `State` can draw with,
+    /// returning a description of the first one that is not.
+    pub fn validate(&self) -> Result<(), String> {
+        let color = self.clear_color;
+        if ![color.r, color.g, color.b, color.a]
+            .iter()
+            .all(|c| (0.0..=1.0).contains(c))
+        {
+            return Err(
+                "clear_color must be 3 or 4 numbers between 0 and 1 (red, green, blue and alpha)"
+                    .to_string(),
+            );
+        }
+        if self.stacks < SphereBuilder::MIN_STACKS || self.stacks > MAX_SLICES {
+            return Err(format!(
+                "stacks {} is not between {} and {}",
+                self.stacks,
+                SphereBuilder::MIN_STACKS,
+                MAX_SLICES
+            ));
+        }
+        if self.sectors < SphereBuilder::MIN_SECTORS || self.sectors > MAX_SLICES {
+            return Err(format!(
+                "sectors {} is not between {} and {}",
+                self.sectors,
+                SphereBuilder::MIN_SECTORS,
+                MAX_SLICES
+            ));
+        }
+        if !(self.displacement >= 0.0 && self.displacement.is_finite()) {
+            return Err(format!(
+                "displacement {} is not a number of 0 or more",
+                self.displacement
+            ));
+        }
+        if !(self.specular >= 0.0 && self.specular.is_finite()) {
+            return Err(format!(
+                "specular {} is not a number of 0 or more",
+                self.specular
+            ));
+        }
+        if color::builtin(&self.colors).is_none() {
+            return Err(format!(
+                "there are no colors called '{}'; the colors are: {}",
+                self.colors,
+                color::BUILTIN.join(", ")
+            ));
+        }
+        let rotation = &self.rotation;
+        if !rotation.axis.iter().all(|v| v.is_finite()) || !rotation.speed.is_finite() {
+            return Err("the rotation axis and speed must be numbers".to_string());
+        }
+        if let Some(bars) = rotation.bars {
+            if !(bars > 0.0 && bars.is_finite()) {
+                return Err(format!("bars {} is not a positive number", bars));
+            }
+        }
+        let camera = &self.camera;
+        if !(camera.fovy > 0.0 && camera.fovy < 180.0) {
+            return Err(format!("fovy {} is not between 0 and 180", camera.fovy));
+        }
+        let finite = |point: &[f32; 3]| point.iter().all(|v| v.is_finite());
+        if !finite(&camera.eye) || !finite(&camera.target) || camera.eye == camera.target {
+            return Err("the camera eye and target must be two different points".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        Self::builtin("classic").unwrap()
+    }
+}
+
+/// an `io::Error` for a preset file that cannot be used.
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+#[test]
+fn test_builtin() {
+    for name in Preset::BUILTIN.iter() {
+        let preset = Preset::builtin(name).unwrap();
+        assert_eq!(preset.name, *name);
+        assert!(preset.validate().is_ok());
+    }
+    assert!(Preset::builtin("disco").is_none());
+}
+#[test]
+fn test_default() {
+    assert_eq!(Preset::default().clear_color, wgpu::Color::WHITE);
+}
+#[test]
+fn test_validate() {
+    let message = |displacement| {
+        Preset {
+            displacement,
+            ..Preset::default()
+        }
+        .validate()
+        .err()
+        .unwrap()
+    };
+    assert_eq!(
+        message(-1.0),
+        "displacement -1 is not a number of 0 or more"
+    );
+    assert_eq!(
+        message(f32::NAN),
+        "displacement NaN is not a number of 0 or more"
+    );
+    assert_eq!(
+        message(f32::INFINITY),
+        "displacement inf is not a number of 0 or more"
+    );
+}
+#[test]
+fn test_parse() {
+    let presets = Preset::parse(
+        r#"
+        [[preset]]
+        name = "ember"
+        clear_color = [0.5, 0.0, 0.0]
+        stacks = 24
+        colors = "energy"
+        rotation = { axis = [0.0, 1.0, 0.0], bars = 4.0 }
+        camera = { fovy = 40.0 }
+
+        [[preset]]
+        name = "plain"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(presets.len(), 2);
+    let ember = &presets[0];
+    assert_eq!(ember.name, "ember");
+    assert_eq!(ember.clear_color.r, 0.5);
+    assert_eq!(ember.clear_color.a, 1.0);
+    assert_eq!((ember.stacks, ember.sectors), (24, 36));
+    assert_eq!(ember.colors, "energy");
+    assert_eq!(ember.rotation.axis, [0.0, 1.0, 0.0]);
+    assert_eq!(ember.rotation.speed, Rotation::default().speed);
+    assert_eq!(ember.rotation.bars, Some(4.0));
+    assert_eq!(ember.camera.fovy, 40.0);
+    assert_eq!(ember.camera.eye, View::default().eye);
+    // everything left out is as in `classic`
+    let plain = Preset {
+        name: "classic".to_string(),
+        ..presets[1].clone()
+    };
+    assert_eq!(plain, Preset::default());
+}
+#[test]
+fn test_invalid() {
+    let error = |source: &str| Preset::parse(source).err().unwrap().to_string();
+    assert!(error("preset = []").contains("at least one preset"));
+    assert!(error("[[preset]]\nname = \"a\"\n[[preset]]\nname = \"a\"").contains("more than one"));
+    assert!(error("[[preset]]\nname = \"a\"\nstacks = 1").contains("stacks 1"));
+    assert!(
+        error("[[preset]]\nname = \"a\"\nclear_color = [2.0, 0.0, 0.0]").contains("clear_color")
+    );
+    assert!(error("[[preset]]\nname = \"a\"\nclear_color = [0.0, 0.0]").contains("clear_color"));
+    assert!(error("[[preset]]\nname = \"a\"\ncolors = \"sepia\"").contains("'sepia'"));
+    assert!(error("[[preset]]\nname = \"a\"\ndisplacement = -1.0").contains("displacement"));
+    assert!(error("[[preset]]\nname = \"a\"\ncamera = { fovy = 180.0 }").contains("fovy"));
+    assert!(error("[[preset]]\nname = \"a\"\ncamera = { eye = [0.0, 0.0, 0.0] }").contains("eye"));
+    assert!(error("[[preset]]\nname = \"a\"\nrotation = { bars = 0.0 }").contains("bars"));
+    // misspelled settings are not silently ignored
+    assert!(error("[[preset]]\nname = \"a\"\nstack = 24").contains("stack"));
+    // and the name of the preset is in the message
+    assert!(error("[[preset]]\nname = \"ember\"\nstacks = 1").starts_with("preset 'ember'"));
+}
@@ -0,0 +1,264 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use super::Camera;
+use cgmath::{InnerSpace, Point3, Vector3};
+use std::f32::consts::FRAC_PI_2;
+use winit::event::{
+    ElementState, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent,
+};
+
+/// radians the camera orbits by per pixel the mouse is dragged.
+const ORBIT_SPEED: f32 = 0.005;
+/// factor the distance is multiplied by per line scrolled.
+const ZOOM_STEP: f32 = 0.9;
+/// share of the distance to the target the camera is panned by per key press.
+const PAN_STEP: f32 = 0.05;
+/// how close and how far the camera may be from its target.
+const MIN_DISTANCE: f32 = 0.2;
+const MAX_DISTANCE: f32 = 20.0;
+/// the camera stops just short of the poles, where `up` would be ambiguous.
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+/// `CameraController` moves the camera around its target in response to the user:
+/// dragging with the left mouse button orbits, scrolling zooms,
+/// `W`, `A`, `S` and `D` pan and `R` puts the camera back where it started.
+///
+/// The camera's position is kept as angles and a distance around the target,
+/// which makes orbiting and zooming simple changes of a single number.
+///
+/// # Examples
+/// ```
+/// # use kartina::{state::camera::controller::CameraController, Camera};
+/// # let mut camera = Camera {
+/// #     eye: (0.0, 1.0, 2.0).into(),
+/// #     target: (0.0, 0.0, 0.0).into(),
+/// #     up: cgmath::Vector3::unit_y(),
+/// #     aspect: 1.0,
+/// #     fovy: 45.0,
+/// #     znear: 0.1,
+/// #     zfar: 100.0,
+/// # };
+/// let mut controller = CameraController::new((0.0, 1.0, 2.0).into(), (0.0, 0.0, 0.0).into());
+/// controller.orbit(100.0, 0.0);
+/// controller.update_camera(&mut camera);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraController {
+    target: Point3<f32>,
+    distance: f32,
+    // angle around the y axis, measured from the z axis
+    yaw: f32,
+    // angle above the x-z plane
+    pitch: f32,
+    // where the camera started, for `reset`
+    home: (Point3<f32>, f32, f32, f32),
+    dragging: bool,
+    cursor: Option<(f64, f64)>,
+}
+
+impl CameraController {
+    /// Create a controller for a camera at `eye`, looking at `target`.
+    pub fn new(eye: Point3<f32>, target: Point3<f32>) -> Self {
+        let offset = eye - target;
+        let distance = offset.magnitude().clamp(MIN_DISTANCE, MAX_DISTANCE);
+        let yaw = offset.x.atan2(offset.z);
+        let pitch = (offset.y / offset.magnitude().max(f32::EPSILON))
+            .asin()
+            .clamp(-MAX_PITCH, MAX_PITCH);
+        Self {
+            target,
+            distance,
+            yaw,
+            pitch,
+            home: (target, distance, yaw, pitch),
+            dragging: false,
+            cursor: None,
+        }
+    }
+
+    /// position of the camera.
+    pub fn eye(&self) -> Point3<f32> {
+        let (yaw, pitch) = (self.yaw, self.pitch);
+        self.target
+            + self.distance
+                * Vector3::new(
+                    pitch.cos() * yaw.sin(),
+                    pitch.sin(),
+                    pitch.cos() * yaw.cos(),
+                )
+    }
+
+    /// Swing the camera around the target by the distance the mouse moved, in pixels.
+    pub fn orbit(&mut self, dx: f32, dy: f32) {
+        self.yaw -= dx * ORBIT_SPEED;
+        self.pitch = (self.pitch + dy * ORBIT_SPEED).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    /// Move the camera towards the target by `lines` scrolled, or away for negative `lines`.
+    pub fn zoom(&mut self, lines: f32) {
+        self.distance = (self.distance * ZOOM_STEP.powf(lines)).clamp(MIN_DISTANCE, MAX_DISTANCE);
+    }
+
+    /// Slide the camera and its target sideways by `right` and upwards by `up` steps,
+    /// as seen from the camera.
+    pub fn pan(&mut self, right: f32, up: f32) {
+        let forward = (self.target - self.eye()).normalize();
+        let right_axis = forward.cross(Vector3::unit_y()).normalize();
+        let up_axis = right_axis.cross(forward);
+        let step = PAN_STEP * self.distance;
+        self.target += step * (right * right_axis + up * up_axis);
+    }
+
+    /// put the camera back where it started.
+    pub fn reset(&mut self) {
+        let (target, distance, yaw, pitch) = self.home;
+        self.target = target;
+        self.distance = distance;
+        self.yaw = yaw;
+        self.pitch = pitch;
+    }
+
+    /// Handle a window event, returning whether it was meant for the camera.
+    pub fn process_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Left,
+                ..
+            } => {
+                self.dragging = *state == ElementState::Pressed;
+                true
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let (x, y) = (position.x, position.y);
+                if let (true, Some((last_x, last_y))) = (self.dragging, self.cursor) {
+                    self.orbit((x - last_x) as f32, (y - last_y) as f32);
+                }
+                self.cursor = Some((x, y));
+                self.dragging
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    // roughly twenty pixels to a line
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 20.0,
+                };
+                self.zoom(lines);
+                true
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(key),
+                        ..
+                    },
+                ..
+            } => {
+                match key {
+                    VirtualKeyCode::A => self.pan(-1.0, 0.0),
+                    VirtualKeyCode::D => self.pan(1.0, 0.0),
+                    VirtualKeyCode::W => self.pan(0.0, 1.0),
+                    VirtualKeyCode::S => self.pan(0.0, -1.0),
+                    VirtualKeyCode::R => self.reset(),
+                    _ => return false,
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Point `camera` the way the controller says.
+    pub fn update_camera(&self, camera: &mut Camera) {
+        camera.eye = self.eye();
+        camera.target = self.target;
+    }
+}
+
+/// whether `a` and `b` are no more than 1e-5 apart.
+#[cfg(test)]
+fn close(a: Point3<f32>, b: Point3<f32>) -> bool {
+    (a - b).magnitude() < 1e-5
+}
+
+#[cfg(test)]
+#[test]
+fn test_new() {
+    let eye = Point3::new(0.0, 1.0, 2.0);
+    let controller = CameraController::new(eye, Point3::new(0.0, 0.0, 0.0));
+    assert!(close(controller.eye(), eye));
+    assert!((controller.distance - 5f32.sqrt()).abs() < 1e-6);
+}
+#[test]
+fn test_orbit() {
+    let target = Point3::new(1.0, 0.0, 0.0);
+    let mut controller = CameraController::new(Point3::new(1.0, 1.0, 2.0), target);
+    controller.orbit(300.0, -150.0);
+    // orbiting never changes the distance to the target
+    assert!(((controller.eye() - target).magnitude() - 5f32.sqrt()).abs() < 1e-5);
+    // nor goes over the top
+    controller.orbit(0.0, 10_000.0);
+    assert_eq!(controller.pitch, MAX_PITCH);
+    controller.orbit(0.0, -20_000.0);
+    assert_eq!(controller.pitch, -MAX_PITCH);
+}
+#[test]
+fn test_zoom() {
+    let mut controller =
+        CameraController::new(Point3::new(0.0, 0.0, 2.0), Point3::new(0.0, 0.0, 0.0));
+    controller.zoom(1.0);
+    assert!((controller.distance - 1.8).abs() < 1e-6);
+    controller.zoom(-2.0);
+    assert!((controller.distance - 2.0 / 0.9).abs() < 1e-5);
+    controller.zoom(100.0);
+    assert_eq!(controller.distance, MIN_DISTANCE);
+    controller.zoom(-1000.0);
+    assert_eq!(controller.distance, MAX_DISTANCE);
+}
+#[test]
+fn test_pan_and_reset() {
+    let eye = Point3::new(0.0, 0.0, 2.0);
+    let mut controller = CameraController::new(eye, Point3::new(0.0, 0.0, 0.0));
+    controller.pan(1.0, 1.0);
+    // looking down -z, right is +x and up is +y
+    assert!(close(controller.target, Point3::new(0.1, 0.1, 0.0)));
+    assert!(close(controller.eye(), Point3::new(0.1, 0.1, 2.0)));
+    controller.orbit(50.0, 20.0);
+    controller.zoom(3.0);
+    controller.reset();
+    assert!(close(controller.eye(), eye));
+}
+#[test]
+fn test_update_camera() {
+    let mut camera = Camera {
+        eye: (0.0, 1.0, 2.0).into(),
+        target: (0.0, 0.0, 0.0).into(),
+        up: Vector3::unit_y(),
+        aspect: 1.0,
+        fovy: 45.0,
+        znear: 0.1,
+        zfar: 100.0,
+    };
+    let mut controller = CameraController::new(camera.eye, camera.target);
+    controller.orbit(0.0, 100.0);
+    controller.update_camera(&mut camera);
+    assert_eq!(camera.eye, controller.eye());
+    assert_eq!(camera.target, controller.target);
+}
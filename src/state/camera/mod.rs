@@ -16,6 +16,9 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+pub mod controller;
+pub mod path;
+
 /// this is a `camera` data structure to keep
 /// track of all information related to the window's view.
 pub struct Camera {
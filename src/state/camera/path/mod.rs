@@ -0,0 +1,398 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use super::Camera;
+use crate::analysis::beat::Beat;
+use cgmath::{InnerSpace, Point3, Quaternion, Rotation, Vector3};
+use serde::Deserialize;
+use std::{fs, io, path::Path, time::Duration};
+
+/// How the camera moves between two keyframes.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Interpolation {
+    /// in a straight line, at a constant speed.
+    Linear,
+    /// along a smooth curve through every keyframe.
+    CatmullRom,
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Interpolation::CatmullRom
+    }
+}
+
+/// Where the camera is at `time` seconds into the song.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct Keyframe {
+    pub time: f32,
+    pub eye: [f32; 3],
+    pub target: [f32; 3],
+    #[serde(default = "Keyframe::default_fovy")]
+    pub fovy: f32,
+}
+
+impl Keyframe {
+    fn default_fovy() -> f32 {
+        45.0
+    }
+}
+
+/// a camera path as it is written in a file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PathFile {
+    #[serde(default)]
+    interpolation: Interpolation,
+    #[serde(default)]
+    snap_to_beats: bool,
+    keyframe: Vec<Keyframe>,
+}
+
+/// `CameraPath` moves the camera through a list of keyframes, timed to the song.
+///
+/// The point the camera looks at follows the keyframes' `target`s, in straight lines or along
+/// a Catmull-Rom spline. The camera itself is placed around that point: the direction it looks
+/// from turns at a steady rate from one keyframe's to the next (spherical linear interpolation)
+/// while its distance changes evenly, so moving between two views of the same target is an arc
+/// around it rather than a cut through it. Before the first keyframe and after the last one
+/// the camera stays put.
+///
+/// When `snap_to_beats` is set, keyframes that are still to come are moved onto the
+/// beat grid predicted from the latest beat, so that the camera moves in time with the music.
+///
+/// Paths are written in TOML:
+/// ```toml
+/// interpolation = "catmull-rom" # or "linear"
+/// snap_to_beats = true
+///
+/// [[keyframe]]
+/// time = 0.0
+/// eye = [0.0, 1.0, 2.0]
+/// target = [0.0, 0.0, 0.0]
+/// fovy = 45.0 # optional
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraPath {
+    interpolation: Interpolation,
+    snap_to_beats: bool,
+    // keyframes in the order they are reached, as written
+    keyframes: Vec<Keyframe>,
+    // times the keyframes are reached at, once snapped to the beat
+    times: Vec<f32>,
+}
+
+impl CameraPath {
+    /// Read a path from the TOML file at `path`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Read a path from TOML `source`.
+    /// Fails if there are no keyframes, if a keyframe's field of view
+    /// or time is out of range, or if its eye is on its target.
+    pub fn parse(source: &str) -> io::Result<Self> {
+        let file: PathFile =
+            toml::from_str(source).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        if file.keyframe.is_empty() {
+            return invalid("a camera path needs at least one keyframe".to_string());
+        }
+        for keyframe in &file.keyframe {
+            if keyframe.fovy.is_nan() || keyframe.fovy <= 0.0 || keyframe.fovy >= 180.0 {
+                return invalid(format!("fovy {} is not between 0 and 180", keyframe.fovy));
+            }
+            if keyframe.time.is_nan() || keyframe.time < 0.0 {
+                return invalid(format!("time {} is before the song starts", keyframe.time));
+            }
+            // the camera would have no direction to look in
+            if keyframe.eye == keyframe.target {
+                return invalid(format!(
+                    "eye {:?} is the same as target at time {}",
+                    keyframe.eye, keyframe.time
+                ));
+            }
+        }
+        let mut keyframes = file.keyframe;
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        Ok(Self {
+            interpolation: file.interpolation,
+            snap_to_beats: file.snap_to_beats,
+            times: keyframes.iter().map(|keyframe| keyframe.time).collect(),
+            keyframes,
+        })
+    }
+
+    /// Move the keyframes after `beat` onto the beat grid that follows from it,
+    /// if the path snaps to beats and the tempo is known.
+    /// The time of the beat is its position in the song, like the keyframes' times,
+    /// which is what the beat tracker reports when it is fed each frame's position in its track.
+    pub fn snap(&mut self, beat: &Beat) {
+        let bpm = match beat.bpm {
+            Some(bpm) if self.snap_to_beats && bpm > 0.0 => bpm,
+            _ => return,
+        };
+        let now = beat.time.as_secs_f32();
+        let period = 60.0 / bpm;
+        for (time, keyframe) in self.times.iter_mut().zip(&self.keyframes) {
+            if keyframe.time > now {
+                *time = now + ((keyframe.time - now) / period).round() * period;
+            }
+        }
+    }
+
+    /// Put the keyframes back at the times they were written with,
+    /// for a track that starts over on a beat grid of its own.
+    pub fn reset(&mut self) {
+        for (time, keyframe) in self.times.iter_mut().zip(&self.keyframes) {
+            *time = keyframe.time;
+        }
+    }
+
+    /// Place `camera` where the path has it at `now`.
+    pub fn apply(&self, now: Duration, camera: &mut Camera) {
+        let (eye, target, fovy) = self.pose(now.as_secs_f32());
+        camera.eye = eye;
+        camera.target = target;
+        camera.fovy = fovy;
+    }
+
+    /// eye, target and field of view of the camera `time` seconds into the song.
+    fn pose(&self, time: f32) -> (Point3<f32>, Point3<f32>, f32) {
+        let last = self.keyframes.len() - 1;
+        // index of the keyframe the camera is leaving, and how far it is to the next
+        let (i, t) = match self.times.iter().rposition(|&start| start <= time) {
+            None => (0, 0.0),
+            Some(i) if i == last => (last, 0.0),
+            Some(i) => {
+                let span = self.times[i + 1] - self.times[i];
+                (
+                    i,
+                    if span > 0.0 {
+                        (time - self.times[i]) / span
+                    } else {
+                        1.0
+                    },
+                )
+            }
+        };
+        let from = &self.keyframes[i];
+        let to = &self.keyframes[(i + 1).min(last)];
+
+        let target = match self.interpolation {
+            Interpolation::Linear => lerp(from.target, to.target, t),
+            Interpolation::CatmullRom => catmull_rom(
+                self.keyframes[i.saturating_sub(1)].target,
+                from.target,
+                to.target,
+                self.keyframes[(i + 2).min(last)].target,
+                t,
+            ),
+        };
+        // swing around the target along the shortest arc
+        let (from_offset, to_offset) = (offset(from), offset(to));
+        let turn = Quaternion::from_arc(from_offset.normalize(), to_offset.normalize(), None);
+        let direction = Quaternion::new(1.0, 0.0, 0.0, 0.0)
+            .slerp(turn, t)
+            .rotate_vector(from_offset.normalize());
+        let distance =
+            from_offset.magnitude() + t * (to_offset.magnitude() - from_offset.magnitude());
+        let target = Point3::from(target);
+        (
+            target + direction * distance,
+            target,
+            from.fovy + t * (to.fovy - from.fovy),
+        )
+    }
+}
+
+/// the direction and distance from a keyframe's target to its eye.
+fn offset(keyframe: &Keyframe) -> Vector3<f32> {
+    Point3::from(keyframe.eye) - Point3::from(keyframe.target)
+}
+
+fn lerp(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    let mut point = [0.0; 3];
+    for axis in 0..3 {
+        point[axis] = a[axis] + t * (b[axis] - a[axis]);
+    }
+    point
+}
+
+/// Point `t` of the way from `p1` to `p2` on a uniform Catmull-Rom spline,
+/// where `p0` and `p3` are the points before and after them.
+fn catmull_rom(p0: [f32; 3], p1: [f32; 3], p2: [f32; 3], p3: [f32; 3], t: f32) -> [f32; 3] {
+    let (t2, t3) = (t * t, t * t * t);
+    let mut point = [0.0; 3];
+    for axis in 0..3 {
+        point[axis] = 0.5
+            * (2.0 * p1[axis]
+                + (p2[axis] - p0[axis]) * t
+                + (2.0 * p0[axis] - 5.0 * p1[axis] + 4.0 * p2[axis] - p3[axis]) * t2
+                + (3.0 * p1[axis] - p0[axis] - 3.0 * p2[axis] + p3[axis]) * t3);
+    }
+    point
+}
+
+#[cfg(test)]
+const PATH: &str = r#"
+interpolation = "linear"
+snap_to_beats = true
+
+[[keyframe]]
+time = 4.0
+eye = [2.0, 0.0, 0.0]
+target = [0.0, 0.0, 0.0]
+fovy = 60.0
+
+[[keyframe]]
+time = 0.0
+eye = [0.0, 0.0, 2.0]
+target = [0.0, 0.0, 0.0]
+"#;
+
+/// whether `a` and `b` are no more than 1e-4 apart.
+#[cfg(test)]
+fn close(a: Point3<f32>, b: [f32; 3]) -> bool {
+    (a - Point3::from(b)).magnitude() < 1e-4
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse() {
+    let path = CameraPath::parse(PATH).unwrap();
+    assert_eq!(path.interpolation, Interpolation::Linear);
+    assert!(path.snap_to_beats);
+    // keyframes are sorted by time, and the field of view defaults to 45 degrees
+    assert_eq!(path.times, [0.0, 4.0]);
+    assert_eq!(path.keyframes[0].fovy, 45.0);
+
+    let spline = CameraPath::parse(
+        "[[keyframe]]\ntime = 1.0\neye = [0.0, 0.0, 1.0]\ntarget = [0.0, 0.0, 0.0]",
+    );
+    assert_eq!(spline.unwrap().interpolation, Interpolation::CatmullRom);
+}
+#[test]
+fn test_invalid() {
+    assert!(CameraPath::parse("interpolation = \"linear\"").is_err());
+    assert!(CameraPath::parse("keyframe = []").is_err());
+    assert!(CameraPath::parse(&PATH.replace("60.0", "180.0")).is_err());
+    assert!(CameraPath::parse(&PATH.replace("4.0", "-4.0")).is_err());
+    assert!(CameraPath::parse(&PATH.replace("linear", "cubic")).is_err());
+    let error = CameraPath::parse(&PATH.replace("[2.0, 0.0, 0.0]", "[0.0, 0.0, 0.0]"))
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("the same as target"), "{}", error);
+}
+#[test]
+fn test_pose() {
+    let path = CameraPath::parse(PATH).unwrap();
+    let (eye, target, fovy) = path.pose(2.0);
+    // halfway around the target, at the same distance from it
+    let half = std::f32::consts::SQRT_2;
+    assert!(close(eye, [half, 0.0, half]));
+    assert!(close(target, [0.0, 0.0, 0.0]));
+    assert_eq!(fovy, 52.5);
+    // the camera waits at the ends of the path
+    assert!(close(path.pose(-1.0).0, [0.0, 0.0, 2.0]));
+    assert!(close(path.pose(10.0).0, [2.0, 0.0, 0.0]));
+}
+#[test]
+fn test_slerp() {
+    // the camera moves a quarter turn around a target that moves too
+    let path = CameraPath::parse(
+        r#"
+        interpolation = "linear"
+        [[keyframe]]
+        time = 0.0
+        eye = [1.0, 0.0, 0.0]
+        target = [0.0, 0.0, 0.0]
+        [[keyframe]]
+        time = 1.0
+        eye = [0.0, 3.0, 2.0]
+        target = [0.0, 0.0, 2.0]
+        "#,
+    )
+    .unwrap();
+    let (eye, target, _) = path.pose(0.5);
+    assert!(close(target, [0.0, 0.0, 1.0]));
+    // halfway through, the camera looks from halfway between the two directions,
+    // halfway between the two distances
+    let half = std::f32::consts::FRAC_1_SQRT_2 * 2.0;
+    assert!(close(eye, [half, half, 1.0]));
+}
+#[test]
+fn test_catmull_rom() {
+    let (p0, p1, p2, p3) = ([0.0; 3], [1.0; 3], [2.0; 3], [3.0; 3]);
+    // the spline passes through its control points
+    assert_eq!(catmull_rom(p0, p1, p2, p3, 0.0), p1);
+    assert_eq!(catmull_rom(p0, p1, p2, p3, 1.0), p2);
+    // and points evenly spaced on a line stay on it
+    assert_eq!(catmull_rom(p0, p1, p2, p3, 0.5), [1.5; 3]);
+}
+#[test]
+fn test_snap() {
+    let mut path = CameraPath::parse(PATH).unwrap();
+    let beat = |bpm| Beat {
+        time: Duration::from_millis(500),
+        confidence: 1.0,
+        bpm,
+    };
+    // without a tempo there is no grid
+    path.snap(&beat(None));
+    assert_eq!(path.times, [0.0, 4.0]);
+    // beats every 0.8s from 0.5s: 3.7s and 4.5s are the nearest
+    path.snap(&beat(Some(75.0)));
+    assert_eq!(path.times[0], 0.0);
+    assert!((path.times[1] - 3.7).abs() < 1e-5);
+    path.reset();
+    assert_eq!(path.times, [0.0, 4.0]);
+}
+#[test]
+fn test_snap_after_seek() {
+    use crate::{analysis::beat::BeatTracker, audio::Frame};
+
+    let mut path = CameraPath::parse(&PATH.replace("4.0", "62.0")).unwrap();
+    // a click every half second, from a little over a minute into the song
+    let click: Vec<i16> = (0..22050)
+        .map(|i| ((i as f32 * 0.3).sin() * 20000.0 * (-(i as f32) / 200.0).exp()) as i16)
+        .collect();
+    let mut tracker = BeatTracker::new();
+    let mut position = Duration::from_millis(60_200);
+    for _ in 0..8 {
+        for chunk in click.chunks(1152) {
+            let frame = Frame {
+                data: chunk.to_vec(),
+                sample_rate: 44100,
+                channels: 1,
+            };
+            if let Some(beat) = tracker.process(&frame, position) {
+                path.snap(&Beat {
+                    bpm: Some(120.0),
+                    ..beat
+                });
+            }
+            position += frame.duration();
+        }
+    }
+    // the keyframe lands on the clicks of the song, not on the time since the tracker started
+    let off = (path.times[1] - 60.2 + 0.25) % 0.5 - 0.25;
+    assert!(off.abs() < 0.03, "keyframe at {}", path.times[1]);
+    assert!((path.times[1] - 62.0).abs() < 0.3);
+}
@@ -0,0 +1,312 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::analysis::{Features, BANDS};
+use std::f32::consts::PI;
+
+/// One element of the mesh being colored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Element {
+    /// position of the element on the unit sphere.
+    pub position: [f32; 3],
+    /// index of the element within the mesh.
+    pub id: usize,
+}
+
+impl Element {
+    /// height of the element, from -1.0 at the south pole to 1.0 at the north pole.
+    pub fn latitude(&self) -> f32 {
+        self.position[2]
+    }
+
+    /// angle of the element around the poles, from 0.0 up to (but excluding) 1.0.
+    pub fn longitude(&self) -> f32 {
+        let [x, y, _] = self.position;
+        (y.atan2(x) / (2.0 * PI)).rem_euclid(1.0)
+    }
+}
+
+/// A `ColorMapper` decides how the music is turned into colors:
+/// given the features of a frame, it returns the color of each element of the sphere.
+///
+/// The sphere is colored in the vertex shader, which runs the mapper `uniforms` picks
+/// with the parameters they carry; `color` gives the same colors on the CPU.
+pub trait ColorMapper {
+    fn color(&self, features: &Features, element: &Element) -> [f32; 3];
+    fn uniforms(&self) -> MapperUniforms;
+}
+
+/// most colors a palette can hold, as many as the vertex shader has room for.
+pub const PALETTE: usize = 8;
+
+/// which of the vertex shader's mappers colors the sphere.
+const LATITUDE_BANDS: u32 = 0;
+const SPECTRUM_HUE: u32 = 1;
+const ENERGY_GRADIENT: u32 = 2;
+const PALETTE_LOOKUP: u32 = 3;
+
+/// A color mapper, in the layout of the mapper fields of the `Audio` uniform block
+/// of the vertex shader.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MapperUniforms {
+    // the mapper the shader runs, how many colors of the palette it picks from,
+    // and how many frequency bands there are
+    mapper: [u32; 4],
+    palette: [[f32; 4]; PALETTE],
+}
+
+impl MapperUniforms {
+    fn new(mapper: u32) -> Self {
+        Self {
+            mapper: [mapper, 0, BANDS as u32, 0],
+            ..Self::default()
+        }
+    }
+}
+
+/// names of the color mappers that ship with kartina.
+pub const BUILTIN: [&str; 4] = ["bands", "hue", "energy", "palette"];
+
+/// Look up one of the built-in color mappers by `name`.
+/// Returns `None` if there is no mapper with that name.
+///
+/// # Examples
+/// ```
+/// # use kartina::state::color;
+/// let mapper = color::builtin("hue").unwrap();
+/// ```
+pub fn builtin(name: &str) -> Option<Box<dyn ColorMapper>> {
+    match name {
+        "bands" => Some(Box::new(LatitudeBands)),
+        "hue" => Some(Box::new(SpectrumHue)),
+        "energy" => Some(Box::new(EnergyGradient)),
+        "palette" => Some(Box::new(Palette::default())),
+        _ => None,
+    }
+}
+
+/// the frequency band assigned to `position` between 0.0 and 1.0.
+fn band(position: f32) -> usize {
+    (position.clamp(0.0, 1.0) * (BANDS - 1) as f32).round() as usize
+}
+
+/// Convert a color from hue, saturation and value, all between 0.0 and 1.0, to RGB.
+fn hsv(hue: f32, saturation: f32, value: f32) -> [f32; 3] {
+    let h = hue.rem_euclid(1.0) * 6.0;
+    let c = value * saturation;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = value - c;
+    [r + m, g + m, b + m]
+}
+
+/// The red, green and blue channels follow the bass, mid and treble levels,
+/// and are brightened by the overall loudness and by the level of the frequency band
+/// assigned to the element's latitude: low frequencies at the bottom, high frequencies at the top.
+pub struct LatitudeBands;
+
+impl ColorMapper for LatitudeBands {
+    fn color(&self, features: &Features, element: &Element) -> [f32; 3] {
+        let level = features.bands[band((element.latitude() + 1.0) / 2.0)];
+        let brightness = 0.25 + features.loudness().min(0.5) + 0.5 * level;
+        [
+            features.bass * brightness,
+            features.mid * brightness,
+            features.treble * brightness,
+        ]
+    }
+
+    fn uniforms(&self) -> MapperUniforms {
+        MapperUniforms::new(LATITUDE_BANDS)
+    }
+}
+
+/// The spectrum is wrapped around the sphere, one band per slice of longitude.
+/// Each band has its own hue, from red for the lowest to violet for the highest,
+/// and shines as brightly as it is loud.
+pub struct SpectrumHue;
+
+impl ColorMapper for SpectrumHue {
+    fn color(&self, features: &Features, element: &Element) -> [f32; 3] {
+        let band = band(element.longitude());
+        let hue = 0.8 * band as f32 / (BANDS - 1) as f32;
+        hsv(hue, 1.0, 0.15 + 0.85 * features.bands[band])
+    }
+
+    fn uniforms(&self) -> MapperUniforms {
+        MapperUniforms::new(SPECTRUM_HUE)
+    }
+}
+
+/// The whole sphere goes from a cold blue when the music is quiet to a hot red when it is loud,
+/// shaded from dark at the bottom to bright at the top.
+pub struct EnergyGradient;
+
+impl EnergyGradient {
+    const COLD: [f32; 3] = [0.1, 0.2, 0.8];
+    const HOT: [f32; 3] = [1.0, 0.3, 0.1];
+}
+
+impl ColorMapper for EnergyGradient {
+    fn color(&self, features: &Features, element: &Element) -> [f32; 3] {
+        let t = (2.0 * features.loudness()).min(1.0);
+        let shade = 0.5 + 0.25 * (element.latitude() + 1.0);
+        let mut color = [0.0; 3];
+        for (i, channel) in color.iter_mut().enumerate() {
+            *channel = (Self::COLD[i] + t * (Self::HOT[i] - Self::COLD[i])) * shade;
+        }
+        color
+    }
+
+    fn uniforms(&self) -> MapperUniforms {
+        MapperUniforms::new(ENERGY_GRADIENT)
+    }
+}
+
+/// Every element follows one of the frequency bands, in turn,
+/// and picks its color from `colors` by how loud that band is:
+/// the first color when it is silent, the last when it is at full level.
+pub struct Palette {
+    colors: Vec<[f32; 3]>,
+}
+
+impl Palette {
+    /// A palette of `colors`, from quietest to loudest.
+    /// Returns `None` if there are no colors to pick from, or more than `PALETTE`.
+    pub fn new(colors: Vec<[f32; 3]>) -> Option<Self> {
+        if colors.is_empty() || colors.len() > PALETTE {
+            return None;
+        }
+        Some(Self { colors })
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::new(vec![
+            [0.05, 0.05, 0.15],
+            [0.25, 0.1, 0.45],
+            [0.75, 0.2, 0.45],
+            [1.0, 0.55, 0.2],
+            [1.0, 0.95, 0.6],
+        ])
+        .unwrap()
+    }
+}
+
+impl ColorMapper for Palette {
+    fn color(&self, features: &Features, element: &Element) -> [f32; 3] {
+        let level = features.bands[element.id % BANDS].clamp(0.0, 1.0);
+        self.colors[(level * (self.colors.len() - 1) as f32).round() as usize]
+    }
+
+    fn uniforms(&self) -> MapperUniforms {
+        let mut uniforms = MapperUniforms::new(PALETTE_LOOKUP);
+        uniforms.mapper[1] = self.colors.len() as u32;
+        for (slot, color) in uniforms.palette.iter_mut().zip(&self.colors) {
+            *slot = [color[0], color[1], color[2], 1.0];
+        }
+        uniforms
+    }
+}
+
+/// features of a frame where every band is at `level`.
+#[cfg(test)]
+fn flat(level: f32) -> Features {
+    Features {
+        bands: [level; BANDS],
+        bass: level,
+        mid: level,
+        treble: level,
+        rms: vec![level, level],
+        ..Features::default()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_builtin() {
+    for name in BUILTIN.iter() {
+        assert!(builtin(name).is_some());
+    }
+    assert!(builtin("sepia").is_none());
+}
+#[test]
+fn test_hsv() {
+    assert_eq!(hsv(0.0, 1.0, 1.0), [1.0, 0.0, 0.0]);
+    assert_eq!(hsv(1.0 / 3.0, 1.0, 1.0), [0.0, 1.0, 0.0]);
+    assert_eq!(hsv(0.5, 0.0, 0.5), [0.5, 0.5, 0.5]);
+}
+#[test]
+fn test_element() {
+    let element = |position| Element { position, id: 0 };
+    assert_eq!(element([0.0, 0.0, -1.0]).latitude(), -1.0);
+    assert_eq!(element([1.0, 0.0, 0.0]).longitude(), 0.0);
+    assert_eq!(element([0.0, -1.0, 0.0]).longitude(), 0.75);
+}
+#[test]
+fn test_silence() {
+    let element = Element {
+        position: [0.0, 0.0, 1.0],
+        id: 3,
+    };
+    let silence = flat(0.0);
+    assert_eq!(LatitudeBands.color(&silence, &element), [0.0; 3]);
+    assert_eq!(
+        Palette::default().color(&silence, &element),
+        Palette::default().colors[0]
+    );
+    // quiet music is cold, loud music is hot
+    let cold = EnergyGradient.color(&silence, &element);
+    let hot = EnergyGradient.color(&flat(1.0), &element);
+    assert!(cold[2] > cold[0] && hot[0] > hot[2]);
+}
+#[test]
+fn test_palette() {
+    assert!(Palette::new(vec![]).is_none());
+    assert!(Palette::new(vec![[0.0; 3]; PALETTE + 1]).is_none());
+    let palette = Palette::new(vec![[0.0; 3], [1.0; 3]]).unwrap();
+    let mut features = flat(0.0);
+    features.bands[2] = 1.0;
+    let element = |id| Element {
+        position: [1.0, 0.0, 0.0],
+        id,
+    };
+    // elements follow the bands in turn
+    assert_eq!(palette.color(&features, &element(2)), [1.0; 3]);
+    assert_eq!(palette.color(&features, &element(18)), [1.0; 3]);
+    assert_eq!(palette.color(&features, &element(3)), [0.0; 3]);
+}
+#[test]
+fn test_uniforms() {
+    assert_eq!(LatitudeBands.uniforms().mapper, [LATITUDE_BANDS, 0, 16, 0]);
+    assert_eq!(EnergyGradient.uniforms().mapper[0], ENERGY_GRADIENT);
+    let palette = Palette::new(vec![[0.0; 3], [0.5, 0.25, 1.0]]).unwrap();
+    let uniforms = palette.uniforms();
+    assert_eq!(uniforms.mapper[..2], [PALETTE_LOOKUP, 2]);
+    assert_eq!(uniforms.palette[1], [0.5, 0.25, 1.0, 1.0]);
+    assert_eq!(uniforms.palette[2], [0.0; 4]);
+}
@@ -0,0 +1,87 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::time::Duration;
+
+/// `CrossFade` blends the colors the sphere had when a track ended
+/// into the colors the next track gives it, so that a change of track is not a jump cut.
+/// Halfway through a fade from red to blue, an element is `[0.5, 0.0, 0.5]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrossFade {
+    length: Duration,
+    elapsed: Duration,
+}
+
+impl CrossFade {
+    /// Start fading out of the colors the sphere has now, over `length`.
+    pub fn new(length: Duration) -> Self {
+        Self {
+            length,
+            elapsed: Duration::from_secs(0),
+        }
+    }
+
+    /// Move the fade along by `elapsed`.
+    /// Returns whether it is still running.
+    pub fn advance(&mut self, elapsed: Duration) -> bool {
+        self.elapsed += elapsed;
+        self.elapsed < self.length
+    }
+
+    /// share of the old colors still in the mix, from 1.0 down to 0.0.
+    pub fn weight(&self) -> f32 {
+        if self.length.as_nanos() == 0 {
+            return 0.0;
+        }
+        1.0 - (self.elapsed.as_secs_f32() / self.length.as_secs_f32()).min(1.0)
+    }
+
+    /// Mix `to`, the new color of an element, with `from`, the color it faded out of.
+    /// The vertex shader blends the colors of the sphere the same way.
+    pub fn blend(&self, from: [f32; 3], to: [f32; 3]) -> [f32; 3] {
+        let weight = self.weight();
+        let mut blended = [0.0; 3];
+        for ((blended, from), to) in blended.iter_mut().zip(&from).zip(&to) {
+            *blended = from * weight + to * (1.0 - weight);
+        }
+        blended
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_blend() {
+    let mut fade = CrossFade::new(Duration::from_secs(2));
+    assert_eq!(
+        fade.blend([1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+        [1.0, 0.0, 0.0]
+    );
+    assert!(fade.advance(Duration::from_secs(1)));
+    assert_eq!(
+        fade.blend([1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+        [0.5, 0.0, 0.5]
+    );
+}
+#[test]
+fn test_end() {
+    let mut fade = CrossFade::new(Duration::from_millis(500));
+    assert!(!fade.advance(Duration::from_secs(1)));
+    assert_eq!(fade.weight(), 0.0);
+    assert_eq!(fade.blend([1.0; 3], [0.25; 3]), [0.25; 3]);
+    assert_eq!(CrossFade::new(Duration::from_secs(0)).weight(), 0.0);
+}
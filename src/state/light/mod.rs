@@ -0,0 +1,126 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::analysis::Features;
+
+/// `Light` is a directional light, like the sun, plus an ambient light that reaches
+/// every part of the sphere. Surfaces facing the light are lit by it (diffuse), and
+/// those that reflect it towards the camera get a highlight (Blinn-Phong specular).
+///
+/// The music drives the light: it grows brighter as the music gets louder,
+/// and is tinted by the bass, mid and treble levels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Light {
+    /// direction the light travels in.
+    pub direction: [f32; 3],
+    /// share of the color every surface keeps, even when facing away from the light.
+    pub ambient: f32,
+    /// strength of the highlights, 0.0 for none.
+    pub specular: f32,
+    /// how tight the highlights are; higher is shinier.
+    pub shininess: f32,
+    color: [f32; 3],
+    intensity: f32,
+}
+
+impl Light {
+    pub fn new(specular: f32) -> Self {
+        Self {
+            direction: [-0.4, -0.6, -0.7],
+            ambient: 0.35,
+            specular,
+            shininess: 32.0,
+            color: [1.0; 3],
+            intensity: 0.75,
+        }
+    }
+
+    /// A light that leaves every color as it is: all ambient, with no direction or highlights.
+    pub fn flat() -> Self {
+        Self {
+            direction: [0.0; 3],
+            ambient: 1.0,
+            specular: 0.0,
+            shininess: 1.0,
+            color: [1.0; 3],
+            intensity: 0.0,
+        }
+    }
+
+    /// Follow the music: the light is brightest for loud music,
+    /// and leans towards red, green or blue as the bass, mid or treble is strongest.
+    pub fn react(&mut self, features: &Features) {
+        self.intensity = 0.5 + features.loudness().min(0.5);
+        let levels = [features.bass, features.mid, features.treble];
+        for (channel, level) in self.color.iter_mut().zip(&levels) {
+            *channel = 0.7 + 0.3 * level;
+        }
+    }
+
+    /// the light in the layout of the `Light` uniform block of the fragment shader.
+    pub fn uniforms(&self) -> LightUniforms {
+        let [x, y, z] = self.direction;
+        let length = (x * x + y * y + z * z).sqrt().max(f32::EPSILON);
+        let [r, g, b] = self.color;
+        LightUniforms {
+            direction: [x / length, y / length, z / length, self.ambient],
+            color: [r, g, b, self.intensity],
+            specular: [self.specular, self.shininess, 0.0, 0.0],
+        }
+    }
+}
+
+/// `Light` as the fragment shader reads it.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightUniforms {
+    // normalized direction, and the ambient share
+    direction: [f32; 4],
+    // color, and intensity
+    color: [f32; 4],
+    // strength and shininess of the highlights
+    specular: [f32; 4],
+}
+
+#[cfg(test)]
+#[test]
+fn test_uniforms() {
+    let mut light = Light::new(0.5);
+    light.direction = [0.0, -2.0, 0.0];
+    let uniforms = light.uniforms();
+    assert_eq!(uniforms.direction, [0.0, -1.0, 0.0, 0.35]);
+    assert_eq!(uniforms.specular, [0.5, 32.0, 0.0, 0.0]);
+    assert_eq!(std::mem::size_of::<LightUniforms>(), 3 * 16);
+}
+#[test]
+fn test_react() {
+    let mut light = Light::new(0.5);
+    let quiet = Features {
+        rms: vec![0.0, 0.0],
+        ..Features::default()
+    };
+    light.react(&quiet);
+    assert_eq!(light.uniforms().color, [0.7, 0.7, 0.7, 0.5]);
+    let loud = Features {
+        bass: 1.0,
+        rms: vec![0.8, 0.8],
+        ..Features::default()
+    };
+    light.react(&loud);
+    assert_eq!(light.uniforms().color, [1.0, 0.7, 0.7, 1.0]);
+}
@@ -0,0 +1,235 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use super::vertex::Vertex;
+use std::convert::TryFrom;
+use wgpu::util::DeviceExt;
+
+/// Indices of a `Mesh`, stored in the narrowest type that can address all of its vertices.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Indices {
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+}
+
+impl Indices {
+    /// number of indices.
+    pub fn len(&self) -> usize {
+        match self {
+            Indices::U16(indices) => indices.len(),
+            Indices::U32(indices) => indices.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the index format wgpu has to read these indices with.
+    pub fn format(&self) -> wgpu::IndexFormat {
+        match self {
+            Indices::U16(_) => wgpu::IndexFormat::Uint16,
+            Indices::U32(_) => wgpu::IndexFormat::Uint32,
+        }
+    }
+
+    /// the indices as the bytes copied to the GPU.
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Indices::U16(indices) => bytemuck::cast_slice(indices),
+            Indices::U32(indices) => bytemuck::cast_slice(indices),
+        }
+    }
+}
+
+/// `Mesh` owns a list of vertices and the indices of the triangles connecting them.
+///
+/// A mesh can only be created with indices that all point at one of its vertices,
+/// and it decides for itself whether they are sent to the GPU as `u16` or `u32`,
+/// so the format the index buffer is drawn with always matches its contents.
+///
+/// # Examples
+/// ```
+/// # use kartina::{Mesh, Vertex};
+/// let vertex = |x, y| Vertex { position: [x, y, 0.0], color: [1.0; 3], normal: [0.0, 0.0, 1.0] };
+/// let vertices = vec![vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(0.0, 1.0)];
+/// let mesh = Mesh::new(vertices, vec![0, 1, 2]).unwrap();
+/// assert!(mesh.indices().format() == wgpu::IndexFormat::Uint16);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    vertices: Vec<Vertex>,
+    indices: Indices,
+}
+
+impl Mesh {
+    /// Create a mesh from triangles given as `indices` into `vertices`.
+    /// Returns `None` if an index is out of bounds,
+    /// or if the indices do not make up whole triangles.
+    pub fn new(vertices: Vec<Vertex>, indices: Vec<u32>) -> Option<Self> {
+        if indices.len() % 3 != 0 || indices.iter().any(|&i| i as usize >= vertices.len()) {
+            return None;
+        }
+        // 0xFFFF is kept free, since it restarts strips in `Uint16` index buffers
+        let indices = if vertices.len() < u16::MAX as usize {
+            Indices::U16(
+                indices
+                    .into_iter()
+                    .map(|i| u16::try_from(i).unwrap())
+                    .collect(),
+            )
+        } else {
+            Indices::U32(indices)
+        };
+        Some(Self { vertices, indices })
+    }
+
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+
+    pub fn indices(&self) -> &Indices {
+        &self.indices
+    }
+
+    /// Give every triangle vertices of its own, so that no two triangles share a vertex.
+    /// The triangles of the new mesh are in the same order, three consecutive vertices each,
+    /// and can be colored independently of their neighbours.
+    pub fn flatten(&self) -> Self {
+        let indices: Vec<u32> = match &self.indices {
+            Indices::U16(indices) => indices.iter().map(|&i| i as u32).collect(),
+            Indices::U32(indices) => indices.clone(),
+        };
+        let vertices = indices.iter().map(|&i| self.vertices[i as usize]).collect();
+        // every corner of every triangle is now a vertex of its own
+        Self::new(vertices, (0..indices.len() as u32).collect()).unwrap()
+    }
+
+    /// Copy the mesh into new vertex and index buffers on `device`.
+    /// The vertex buffer can be written to afterwards, to recolor the mesh.
+    pub fn upload(&self, device: &wgpu::Device) -> GpuMesh {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Vertex Buffer"),
+            contents: bytemuck::cast_slice(&self.vertices),
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Index Buffer"),
+            contents: self.indices.as_bytes(),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+        GpuMesh {
+            vertex_buffer,
+            index_buffer,
+            index_format: self.indices.format(),
+            num_indices: self.indices.len() as u32,
+        }
+    }
+}
+
+/// The buffers of a `Mesh` that has been uploaded to the GPU.
+pub struct GpuMesh {
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    index_format: wgpu::IndexFormat,
+    num_indices: u32,
+}
+
+impl GpuMesh {
+    /// Replace the vertices, e.g. to change their colors.
+    /// `vertices` must be as long as those of the mesh that was uploaded.
+    pub fn write_vertices(&self, queue: &wgpu::Queue, vertices: &[Vertex]) {
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(vertices));
+    }
+
+    /// Bind the buffers to `render_pass` and draw every triangle.
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), self.index_format);
+        render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
+    }
+}
+
+/// `count` vertices at the origin, for testing.
+#[cfg(test)]
+fn points(count: usize) -> Vec<Vertex> {
+    vec![
+        Vertex {
+            position: [0.0, 0.0, 0.0],
+            color: [0.0, 0.0, 0.0],
+            normal: [0.0, 0.0, 1.0],
+        };
+        count
+    ]
+}
+
+#[cfg(test)]
+#[test]
+fn test_index_format() {
+    let mesh = Mesh::new(points(3), vec![0, 1, 2]).unwrap();
+    assert_eq!(mesh.indices(), &Indices::U16(vec![0, 1, 2]));
+    assert_eq!(mesh.indices().format(), wgpu::IndexFormat::Uint16);
+
+    let last = u16::MAX as u32;
+    let mesh = Mesh::new(points(last as usize + 1), vec![0, 1, last]).unwrap();
+    assert_eq!(mesh.indices(), &Indices::U32(vec![0, 1, last]));
+    assert_eq!(mesh.indices().format(), wgpu::IndexFormat::Uint32);
+    assert_eq!(mesh.indices().as_bytes().len(), 3 * 4);
+}
+#[test]
+fn test_index_bounds() {
+    assert!(Mesh::new(points(3), vec![0, 1, 3]).is_none());
+    assert!(Mesh::new(points(70_000), vec![0, 1, 70_000]).is_none());
+    // a triangle is missing a corner
+    assert!(Mesh::new(points(3), vec![0, 1]).is_none());
+    assert!(Mesh::new(points(0), vec![]).is_some());
+}
+#[test]
+fn test_sphere_bounds() {
+    use super::vertex::SphereBuilder;
+
+    for &(stacks, sectors) in &[(18, 36), (200, 400)] {
+        let mesh = SphereBuilder::new().stacks(stacks).sectors(sectors).build();
+        let count = mesh.vertices().len();
+        match mesh.indices() {
+            Indices::U16(indices) => assert!(indices.iter().all(|&i| (i as usize) < count)),
+            Indices::U32(indices) => assert!(indices.iter().all(|&i| (i as usize) < count)),
+        }
+        assert_eq!(
+            mesh.indices().format() == wgpu::IndexFormat::Uint16,
+            count < u16::MAX as usize
+        );
+    }
+}
+#[test]
+fn test_flatten() {
+    use super::vertex::SphereBuilder;
+
+    let mesh = SphereBuilder::new().build();
+    let flat = mesh.flatten();
+    assert_eq!(flat.vertices().len(), mesh.indices().len());
+    assert_eq!(flat.indices().len(), mesh.indices().len());
+    let indices = match mesh.indices() {
+        Indices::U16(indices) => indices,
+        Indices::U32(_) => unreachable!(),
+    };
+    // the triangles are where they were
+    for (vertex, &i) in flat.vertices().iter().zip(indices) {
+        assert_eq!(vertex.position, mesh.vertices()[i as usize].position);
+    }
+}
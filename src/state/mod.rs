@@ -16,36 +16,59 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use minimp3::Frame;
-use std::iter;
+use crate::{
+    analysis::{self, beat::Beat, envelope::Envelope},
+    error::Error,
+    preset::Preset,
+};
+use std::{iter, time::Duration};
 use wgpu::util::DeviceExt;
-use winit::window::Window;
+use winit::{event::WindowEvent, window::Window};
 
-mod camera;
-mod vertex;
+pub mod camera;
+pub mod color;
+mod fade;
+mod light;
+pub mod mesh;
+mod overlay;
+pub mod rotation;
+pub mod target;
+pub mod texture;
+pub mod vertex;
 
 /// This structure is necessary to `stage`
 /// the uniforms that correspond to the `camera` view.
 struct UniformStaging {
     camera: camera::Camera,
+    // unit vector the sphere turns around
+    rotation_axis: cgmath::Vector3<f32>,
     model_rotation: cgmath::Deg<f32>,
+    // how far the sphere is swollen by the last beat, between 0.0 and 1.0
+    pulse: f32,
 }
 
 impl UniformStaging {
-    fn new(camera: camera::Camera) -> Self {
+    fn new(camera: camera::Camera, rotation_axis: cgmath::Vector3<f32>) -> Self {
         Self {
             camera,
+            rotation_axis,
             model_rotation: cgmath::Deg(0.0),
+            pulse: 0.0,
         }
     }
 
     /// update the uniforms with the necessary information
     /// so that the window will have the appropriate camera view.
+    /// The model matrix is kept apart, since lighting happens before the camera is applied.
     fn update_uniforms(&self, uniforms: &mut Uniforms) {
-        uniforms.view_proj = (camera::OPENGL_TO_WGPU_MATRIX
-            * self.camera.build_view_projection_matrix()
-            * cgmath::Matrix4::from_angle_z(self.model_rotation))
-        .into();
+        uniforms.view_proj =
+            (camera::OPENGL_TO_WGPU_MATRIX * self.camera.build_view_projection_matrix()).into();
+        uniforms.model =
+            (cgmath::Matrix4::from_axis_angle(self.rotation_axis, self.model_rotation)
+                * cgmath::Matrix4::from_scale(1.0 + 0.25 * self.pulse))
+            .into();
+        let eye = self.camera.eye;
+        uniforms.eye = [eye.x, eye.y, eye.z, 1.0];
     }
 }
 
@@ -58,6 +81,10 @@ struct Uniforms {
     // cgmath cannot be used with bytemuck directly;
     // Matrix4 must be converted into 4x4 `[f32]`.
     view_proj: [[f32; 4]; 4],
+    // rotation and scale of the sphere
+    model: [[f32; 4]; 4],
+    // position of the camera, for the highlights
+    eye: [f32; 4],
 }
 
 impl Uniforms {
@@ -65,6 +92,49 @@ impl Uniforms {
         use cgmath::SquareMatrix;
         Self {
             view_proj: cgmath::Matrix4::identity().into(),
+            model: cgmath::Matrix4::identity().into(),
+            eye: [0.0; 4],
+        }
+    }
+}
+
+/// fraction of the beat pulse left after a 60th of a second.
+const PULSE_DECAY: f32 = 0.85;
+/// time constants of the band envelopes that push the surface of the sphere around.
+const ATTACK: Duration = Duration::from_millis(30);
+const RELEASE: Duration = Duration::from_millis(250);
+/// how long the colors of one track take to blend into those of the next.
+const CROSSFADE: Duration = Duration::from_secs(2);
+
+/// The music, in the layout of the `Audio` uniform block of the vertex shader,
+/// which deforms the sphere by the bands, colors it with the color mapper
+/// and applies the beat to its colors.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct AudioUniforms {
+    // smoothed levels of the frequency bands
+    bands: [f32; analysis::BANDS],
+    // how far the sphere is swollen by the last beat, the rotation of the color channels,
+    // how far the bands push the surface out, and the share of the colors of the last track
+    beat: [f32; 4],
+    // levels of the frequency bands in the frame being heard
+    levels: [f32; analysis::BANDS],
+    // bass, mid, treble and loudness of the frame being heard
+    features: [f32; 4],
+    mapper: color::MapperUniforms,
+}
+
+impl AudioUniforms {
+    /// the features the color mapper was last given, for coloring the sphere on the CPU.
+    fn features(&self) -> analysis::Features {
+        let [bass, mid, treble, loudness] = self.features;
+        analysis::Features {
+            bands: self.levels,
+            bass,
+            mid,
+            treble,
+            rms: vec![loudness],
+            ..analysis::Features::default()
         }
     }
 }
@@ -78,20 +148,42 @@ impl Uniforms {
 /// whose format is specified when they are created so that the GPU can then read those
 /// buffers and render the appropriate image.
 pub struct State {
-    surface: wgpu::Surface,
     device: wgpu::Device,
     queue: wgpu::Queue,
-    sc_desc: wgpu::SwapChainDescriptor,
-    swap_chain: wgpu::SwapChain,
+    target: target::RenderTarget,
+    depth_texture: texture::Texture,
     clear_color: wgpu::Color,
     uniforms: Uniforms,
     uniform_staging: UniformStaging,
+    controller: camera::controller::CameraController,
+    // when set, the camera follows the path instead of the controller
+    camera_path: Option<camera::path::CameraPath>,
+    rotation: rotation::Rotation,
+    // tempo of the music, once the beat tracker has settled on one
+    bpm: Option<f32>,
     uniform_buffer: wgpu::Buffer,
+    audio: AudioUniforms,
+    audio_buffer: wgpu::Buffer,
+    envelopes: [Envelope; analysis::BANDS],
+    light: light::Light,
+    light_buffer: wgpu::Buffer,
+    displacement: f32,
     uniform_bind_group: wgpu::BindGroup,
     render_pipeline: wgpu::RenderPipeline,
-    vertex_buffer: wgpu::Buffer,
-    index_buffer: wgpu::Buffer,
-    num_indices: u32,
+    // the sphere never changes, the shader colors it from the audio uniforms;
+    // only on a change of track are the colors it fades out of written into its vertices
+    sphere_buffers: mesh::GpuMesh,
+    element_buffer: wgpu::Buffer,
+    vertices: Vec<vertex::Vertex>,
+    // each element is colored as one: a single vertex, or in flat mode the three of a triangle
+    elements: Vec<color::Element>,
+    corners: usize,
+    colors: Box<dyn color::ColorMapper>,
+    // rotation of the color channels, moved along by strong beats
+    color_shift: usize,
+    // while the colors of the last track blend into those of the next one
+    fade: Option<fade::CrossFade>,
+    progress_bar: overlay::ProgressBar,
     pub size: winit::dpi::PhysicalSize<u32>,
 }
 
@@ -103,8 +195,9 @@ impl State {
     //` blocked `Future`s will yield control of the thread to other `Future`s
 
     /// Given a `Window` create a new `State` that
-    /// manages what is drawn in the window.
-    pub async fn new(window: &Window) -> Self {
+    /// manages what is drawn in the window, using the look described by `preset`.
+    /// Fails when there is no graphics adapter that can draw into the window, or it cannot be used.
+    pub async fn new(window: &Window, preset: &Preset) -> Result<Self, Error> {
         let size = window.inner_size();
         // `instance` is a handle to the GPU
         // BackendBit::PRIMARY => Vulkan + Metal + DX12 + Browser WebGPU
@@ -113,15 +206,59 @@ impl State {
         // for more on swapchains: [swap chain](https://en.wikipedia.org/wiki/Swap_chain)
         let surface = unsafe { instance.create_surface(window) };
         // `adapter` is needed to create the device and queue
-        // `features` field in DeviceDescriptor allows us to specify extra features
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
                 power_preference: wgpu::PowerPreference::default(),
                 compatible_surface: Some(&surface),
             })
             .await
-            .unwrap();
-        let (device, queue) = adapter
+            .ok_or(Error::NoAdapter)?;
+        let (device, queue) = Self::request_device(&adapter)
+            .await
+            .map_err(Error::Device)?;
+        let target =
+            target::RenderTarget::window(&device, &adapter, surface, size.width, size.height);
+        Ok(Self::build(device, queue, target, preset))
+    }
+
+    /// Create a `State` that draws `width` by `height` images without a window,
+    /// using the look described by `preset`. The images are read back with `capture`.
+    /// Fails when no graphics adapter is available, or it cannot be used.
+    pub async fn headless(width: u32, height: u32, preset: &Preset) -> Result<Self, Error> {
+        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+        // there is no surface the adapter has to be able to present to
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+            })
+            .await
+            .ok_or(Error::NoAdapter)?;
+        let (device, queue) = Self::request_device(&adapter)
+            .await
+            .map_err(Error::Device)?;
+        let target = target::RenderTarget::offscreen(&device, width, height);
+        Ok(Self::build(device, queue, target, preset))
+    }
+
+    /// Create a `State` that draws into `target` with a `device` and `queue` that were
+    /// requested elsewhere, e.g. by an application that shows the sphere in its own window.
+    /// The images are drawn in the format of the target, at its size.
+    pub fn with_target(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        target: target::RenderTarget,
+        preset: &Preset,
+    ) -> Self {
+        Self::build(device, queue, target, preset)
+    }
+
+    /// Request the device and queue used to talk to the GPU.
+    /// `features` field in DeviceDescriptor allows us to specify extra features
+    async fn request_device(
+        adapter: &wgpu::Adapter,
+    ) -> Result<(wgpu::Device, wgpu::Queue), wgpu::RequestDeviceError> {
+        adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
@@ -131,59 +268,113 @@ impl State {
                 None, // Trace path
             )
             .await
-            .unwrap();
-        let sc_desc = wgpu::SwapChainDescriptor {
-            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
-            format: adapter.get_swap_chain_preferred_format(&surface),
-            width: size.width,
-            height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
-        };
-        let swap_chain = device.create_swap_chain(&surface, &sc_desc);
-        let clear_color = wgpu::Color::WHITE;
+    }
+
+    /// Create the buffers and pipeline that draw the sphere into `target`.
+    fn build(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        target: target::RenderTarget,
+        preset: &Preset,
+    ) -> Self {
+        let (width, height) = target.size();
+        let size = winit::dpi::PhysicalSize::new(width, height);
+        let depth_texture =
+            texture::Texture::create_depth_texture(&device, width, height, "depth_texture");
+        let clear_color = preset.clear_color;
         let vs_module =
             device.create_shader_module(&wgpu::include_spirv!("./shaders/shader.vert.spv"));
         let fs_module =
             device.create_shader_module(&wgpu::include_spirv!("./shaders/shader.frag.spv"));
         let camera = camera::Camera {
-            eye: (0.0, 1.0, 2.0).into(),
-            target: (0.0, 0.0, 0.0).into(),
+            eye: preset.camera.eye.into(),
+            target: preset.camera.target.into(),
             up: cgmath::Vector3::unit_y(),
-            aspect: sc_desc.width as f32 / sc_desc.height as f32,
-            fovy: 45.0,
+            aspect: width as f32 / height as f32,
+            fovy: preset.camera.fovy,
             znear: 0.1,
             zfar: 100.0,
         };
         let mut uniforms = Uniforms::new();
-        let uniform_staging = UniformStaging::new(camera);
+        let controller = camera::controller::CameraController::new(camera.eye, camera.target);
+        let uniform_staging = UniformStaging::new(camera, preset.rotation.axis());
         uniform_staging.update_uniforms(&mut uniforms);
         let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Uniform Buffer"),
             contents: bytemuck::cast_slice(&[uniforms]),
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
         });
+        let colors = colors(preset);
+        let audio = AudioUniforms {
+            mapper: colors.uniforms(),
+            ..AudioUniforms::default()
+        };
+        let audio_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Audio Buffer"),
+            contents: bytemuck::cast_slice(&[audio]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let light = light::Light::new(preset.specular);
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Buffer"),
+            contents: bytemuck::cast_slice(&[light.uniforms()]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
         let uniform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
                 label: Some("uniform_bind_group_layout"),
             });
         let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &uniform_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: audio_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: light_buffer.as_entire_binding(),
+                },
+            ],
             label: Some("uniform_bind_group"),
         });
+        let progress_bar = overlay::ProgressBar::new(&device, &uniform_bind_group_layout);
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
@@ -196,13 +387,13 @@ impl State {
             vertex: wgpu::VertexState {
                 module: &vs_module,
                 entry_point: "main",
-                buffers: &[vertex::Vertex::desc()],
+                buffers: &[vertex::Vertex::desc(), element_desc()],
             },
             fragment: Some(wgpu::FragmentState {
                 module: &fs_module,
                 entry_point: "main",
                 targets: &[wgpu::ColorTargetState {
-                    format: sc_desc.format,
+                    format: target.format(),
                     alpha_blend: wgpu::BlendState::REPLACE,
                     color_blend: wgpu::BlendState::REPLACE,
                     write_mask: wgpu::ColorWrite::ALL,
@@ -216,93 +407,252 @@ impl State {
                 // setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
                 polygon_mode: wgpu::PolygonMode::Fill,
             },
-            depth_stencil: None,
+            // fragments are kept only if they are closer to the camera
+            // than whatever has already been drawn at their pixel
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+                clamp_depth: false,
+            }),
             multisample: wgpu::MultisampleState {
                 count: 1,
                 mask: !0,
                 alpha_to_coverage_enabled: false,
             },
         });
-        let vbo = vertex::Vertex::sphere_vertices(1.0);
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Vertex Buffer"),
-            contents: bytemuck::cast_slice(&vbo),
-            usage: wgpu::BufferUsage::VERTEX,
-        });
-        let ibo = vertex::Vertex::sphere_indices();
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Index Buffer"),
-            contents: bytemuck::cast_slice(&ibo),
-            usage: wgpu::BufferUsage::INDEX,
-        });
-        let num_indices = ibo.len() as u32;
+        let sphere = Sphere::build(&device, preset);
         Self {
-            surface,
             device,
             queue,
-            sc_desc,
-            swap_chain,
+            target,
+            depth_texture,
             clear_color,
             uniforms,
             uniform_staging,
+            controller,
+            camera_path: None,
+            rotation: preset.rotation,
+            bpm: None,
             uniform_buffer,
+            audio,
+            audio_buffer,
+            envelopes: [Envelope::new(ATTACK, RELEASE); analysis::BANDS],
+            displacement: preset.displacement,
+            light,
+            light_buffer,
             uniform_bind_group,
             render_pipeline,
-            vertex_buffer,
-            index_buffer,
-            num_indices,
+            sphere_buffers: sphere.buffers,
+            element_buffer: sphere.element_buffer,
+            vertices: sphere.vertices,
+            elements: sphere.elements,
+            corners: sphere.corners,
+            colors,
+            color_shift: 0,
+            fade: None,
+            progress_bar,
             size,
         }
     }
 
-    /// Resize the window according to `new_size`.
+    /// Resize the window, or the offscreen image, according to `new_size`.
+    /// The depth texture must match the size of the render target, so it is recreated as well.
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        // a minimized window has no area, and textures cannot be empty
+        if new_size.width == 0 || new_size.height == 0 {
+            return;
+        }
         self.size = new_size;
-        self.sc_desc.width = new_size.width;
-        self.sc_desc.height = new_size.height;
-        self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
+        self.target
+            .resize(&self.device, new_size.width, new_size.height);
+        self.depth_texture = texture::Texture::create_depth_texture(
+            &self.device,
+            new_size.width,
+            new_size.height,
+            "depth_texture",
+        );
+        self.uniform_staging.camera.aspect = new_size.width as f32 / new_size.height as f32;
+    }
+
+    /// Switch to the look described by `preset` while the music plays:
+    /// the sphere is rebuilt, and the colors, light, rotation and camera are set anew.
+    /// The beat, the tempo and a camera path carry on as they were.
+    pub fn set_preset(&mut self, preset: &Preset) {
+        let sphere = Sphere::build(&self.device, preset);
+        self.sphere_buffers = sphere.buffers;
+        self.element_buffer = sphere.element_buffer;
+        self.vertices = sphere.vertices;
+        self.elements = sphere.elements;
+        self.corners = sphere.corners;
+        self.colors = colors(preset);
+        self.audio.mapper = self.colors.uniforms();
+        // the colors of the old sphere have nothing to blend into
+        self.fade = None;
+        self.clear_color = preset.clear_color;
+        self.displacement = preset.displacement;
+        self.light.specular = preset.specular;
+        self.rotation = preset.rotation;
+        self.uniform_staging.rotation_axis = preset.rotation.axis();
+        let view = preset.camera;
+        let camera = &mut self.uniform_staging.camera;
+        camera.eye = view.eye.into();
+        camera.target = view.target.into();
+        camera.fovy = view.fovy;
+        self.controller = camera::controller::CameraController::new(camera.eye, camera.target);
     }
 
-    /// Uses a single decoded mp3 frame to generate a vertex buffer for a sphere
-    /// whose vertices are colored according to the frame's data.
-    pub fn input(&mut self, frame: &Frame) -> bool {
-        let mut vertices = vertex::Vertex::sphere_vertices(1.0);
-        for vertex in &mut vertices {
-            let colors = [
-                vertex.position[0] * frame.data[2] as f32 % 256.0,
-                vertex.position[1] + frame.data[1] as f32 % 256.0,
-                vertex.position[2] / frame.data[0] as f32 % 256.0,
-            ];
-            vertex.change_color(colors);
+    /// Have the camera follow `path` through the song, instead of the mouse and keyboard.
+    pub fn set_camera_path(&mut self, path: camera::path::CameraPath) {
+        self.camera_path = Some(path);
+    }
+
+    /// Move the camera to where its path has it at `position` into the song.
+    /// Does nothing when there is no camera path.
+    pub fn seek(&mut self, position: Duration) {
+        if let Some(path) = &self.camera_path {
+            path.apply(position, &mut self.uniform_staging.camera);
         }
+    }
 
-        self.vertex_buffer = self
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsage::VERTEX,
-            });
+    /// Let the camera controller handle a window event.
+    /// Returns whether the event was used, in which case it should not be handled elsewhere.
+    pub fn handle_event(&mut self, event: &WindowEvent) -> bool {
+        self.controller.process_event(event)
+    }
+
+    /// Uses the features of a single decoded mp3 frame to color the vertices of the sphere
+    /// according to the music. How the features become colors is up to the `ColorMapper`
+    /// picked by the preset, which the vertex shader runs on the features handed to it.
+    /// In flat mode every triangle is colored on its own, rather than every vertex.
+    /// Right after a change of track, the colors are blended with those of the last track.
+    ///
+    /// The levels of the bands are smoothed by envelopes that rise quickly and fall slowly,
+    /// and handed to the shader, which pushes the surface of the sphere out by them.
+    /// The light brightens and changes color with the music as well.
+    pub fn input(&mut self, features: &analysis::Features) -> bool {
+        self.light.react(features);
+        let bands = self.audio.bands.iter_mut().zip(&mut self.envelopes);
+        for ((band, envelope), level) in bands.zip(&features.bands) {
+            *band = envelope.follow(*level, features.duration);
+        }
+        self.audio.levels = features.bands;
+        self.audio.features = [
+            features.bass,
+            features.mid,
+            features.treble,
+            features.loudness(),
+        ];
         true
     }
 
-    /// Update the model so that it continually rotates.
+    /// Start blending the colors of the sphere as they are into those of the track
+    /// that has just started, rather than cutting from one to the other.
+    /// The colors as they are are worked out on the CPU, and kept in the vertices for the shader.
+    /// A camera path starts over, off the beats of the last track;
+    /// everything else, from the rotation to the camera controller, carries on as it was.
+    pub fn crossfade(&mut self) {
+        if let Some(path) = &mut self.camera_path {
+            path.reset();
+        }
+        let features = self.audio.features();
+        let elements = self.vertices.chunks_mut(self.corners).zip(&self.elements);
+        for (corners, element) in elements {
+            let mut color = self.colors.color(&features, element);
+            if let Some(fade) = &self.fade {
+                color = fade.blend(corners[0].color, color);
+            }
+            for vertex in corners {
+                vertex.change_color(color);
+            }
+        }
+        self.sphere_buffers
+            .write_vertices(&self.queue, &self.vertices);
+        self.fade = Some(fade::CrossFade::new(CROSSFADE));
+    }
+
+    /// Show how far into the track playback is along the bottom of the image,
+    /// as a `fraction` of its length, in another color while `paused`.
+    /// The bar is hidden when the length of the track is not known, and until this is called.
+    pub fn progress(&mut self, fraction: Option<f32>, paused: bool) {
+        match fraction {
+            Some(fraction) => self.progress_bar.set(&self.queue, fraction, paused),
+            None => self.progress_bar.hide(),
+        }
+    }
+
+    /// React to a `beat`: the sphere pulses by the beat's confidence,
+    /// and the colors jump to the next arrangement on beats the tracker is sure of.
+    pub fn beat(&mut self, beat: &Beat) {
+        self.uniform_staging.pulse = self.uniform_staging.pulse.max(beat.confidence);
+        if beat.confidence > 0.8 {
+            self.color_shift += 1;
+        }
+        if beat.bpm.is_some() {
+            self.bpm = beat.bpm;
+        }
+        if let Some(path) = &mut self.camera_path {
+            path.snap(beat);
+        }
+    }
+
+    /// Update the model so that it continually rotates, and the camera so that it follows the controller.
+    /// `elapsed` is the time since the last update, so that the sphere turns
+    /// as fast at any refresh rate, and in lock with the tempo if the preset asks for it.
     /// Uniform staging must be updated with model rotation,
     /// and the corresponding uniforms must be updated to reflect the model's rotation.
     /// The GPU then reads the new uniform buffer and renders the sphere accordingly.
-    pub fn update(&mut self) {
-        self.uniform_staging.model_rotation += cgmath::Deg(2.0);
+    pub fn update(&mut self, elapsed: Duration) {
+        if self.camera_path.is_none() {
+            self.controller
+                .update_camera(&mut self.uniform_staging.camera);
+        }
+        use cgmath::Angle;
+        // keep the angle within a turn, so that it stays precise however long the song
+        let turn = self.rotation.angle(elapsed, self.bpm);
+        self.uniform_staging.model_rotation =
+            (self.uniform_staging.model_rotation + turn).normalize();
+        if let Some(fade) = &mut self.fade {
+            if !fade.advance(elapsed) {
+                self.fade = None;
+            }
+        }
+        // let the last pulse die down, by 15% every 60th of a second
+        self.uniform_staging.pulse *= PULSE_DECAY.powf(elapsed.as_secs_f32() * 60.0);
         self.uniform_staging.update_uniforms(&mut self.uniforms);
         self.queue.write_buffer(
             &self.uniform_buffer,
             0,
             bytemuck::cast_slice(&[self.uniforms]),
         );
+        self.audio.beat = [
+            self.uniform_staging.pulse,
+            (self.color_shift % 3) as f32,
+            self.displacement,
+            self.fade.as_ref().map_or(0.0, fade::CrossFade::weight),
+        ];
+        self.queue
+            .write_buffer(&self.audio_buffer, 0, bytemuck::cast_slice(&[self.audio]));
+        self.queue.write_buffer(
+            &self.light_buffer,
+            0,
+            bytemuck::cast_slice(&[self.light.uniforms()]),
+        );
     }
 
-    /// Render the image in the window according to the vertex and index buffers.
+    /// Render the image in the window, or offscreen, according to the vertex and index buffers.
     pub fn render(&mut self) -> Result<(), wgpu::SwapChainError> {
-        let frame = self.swap_chain.get_current_frame()?.output;
+        // a window's frame must stay alive until the commands drawing to it are submitted
+        let frame;
+        let view = match &self.target {
+            target::RenderTarget::Window { swap_chain, .. } => {
+                frame = swap_chain.get_current_frame()?.output;
+                &frame.view
+            }
+            target::RenderTarget::Offscreen { texture, .. } => &texture.view,
+        };
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -317,10 +667,10 @@ impl State {
             label: Some("Render Pass"),
             color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
                 // `attachment` informs wgpu what textures to save the colors to
-                // in this case, we have specified frame.view
+                // for a window, this is frame.view
                 // (that was created with swap_chain.get_current_frame())
                 // esentially any colors drawn to this attachment will be drawn to the screen
-                attachment: &frame.view,
+                attachment: view,
                 // `resolve_target` is the texture that will receive the resolved output
                 // This will be the same as `attachment` unless multisampling is enabled
                 resolve_target: None,
@@ -332,17 +682,151 @@ impl State {
                     store: true,
                 },
             }],
-            depth_stencil_attachment: None,
+            // every frame starts with the depth buffer at its furthest value
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
         });
         render_pass.set_pipeline(&self.render_pipeline);
         render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
-        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-        render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
+        render_pass.set_vertex_buffer(1, self.element_buffer.slice(..));
+        self.sphere_buffers.draw(&mut render_pass);
+        self.progress_bar.draw(&mut render_pass);
         // release the mutable borrow
         // so that `finish` may be called by encoder.
         drop(render_pass);
         self.queue.submit(iter::once(encoder.finish()));
         Ok(())
     }
+
+    /// Read the last rendered image back from the GPU.
+    /// Only a `State` created with `headless` keeps its images; for a window this returns `None`.
+    pub async fn capture(&self) -> Option<image::RgbaImage> {
+        self.target.read_image(&self.device, &self.queue).await
+    }
+}
+
+/// The sphere as it is drawn: its buffers on the GPU, and the vertices and elements
+/// that are recolored when the track changes.
+struct Sphere {
+    buffers: mesh::GpuMesh,
+    // the element of every vertex, for the shader to color
+    element_buffer: wgpu::Buffer,
+    vertices: Vec<vertex::Vertex>,
+    // each element is colored as one: a single vertex, or in flat mode the three of a triangle
+    elements: Vec<color::Element>,
+    corners: usize,
+}
+
+impl Sphere {
+    /// Build the sphere `preset` describes and upload it to `device`.
+    fn build(device: &wgpu::Device, preset: &Preset) -> Self {
+        let mut sphere = vertex::SphereBuilder::new()
+            .radius(0.1)
+            .stacks(preset.stacks)
+            .sectors(preset.sectors)
+            .build();
+        let corners = if preset.flat {
+            sphere = sphere.flatten();
+            3
+        } else {
+            1
+        };
+        log::info!(
+            "sphere of {} vertices and {} triangles",
+            sphere.vertices().len(),
+            sphere.indices().len() / 3
+        );
+        let vertices = sphere.vertices().to_vec();
+        let elements: Vec<color::Element> = vertices
+            .chunks(corners)
+            .enumerate()
+            .map(|(id, corners)| {
+                // the center of the element, pushed back out onto the unit sphere
+                let mut position = [0.0; 3];
+                for vertex in corners {
+                    for (axis, value) in position.iter_mut().zip(&vertex.position) {
+                        *axis += value;
+                    }
+                }
+                let length = position.iter().map(|v| v * v).sum::<f32>().sqrt();
+                color::Element {
+                    position: [
+                        position[0] / length,
+                        position[1] / length,
+                        position[2] / length,
+                    ],
+                    id,
+                }
+            })
+            .collect();
+        let attributes: Vec<[f32; 4]> = elements
+            .iter()
+            .flat_map(|element| {
+                let [x, y, z] = element.position;
+                iter::repeat([x, y, z, element.id as f32]).take(corners)
+            })
+            .collect();
+        Self {
+            buffers: sphere.upload(device),
+            element_buffer: element_buffer(device, &attributes),
+            vertices,
+            elements,
+            corners,
+        }
+    }
+}
+
+/// Copy `elements` to a new vertex buffer on `device`, laid out as `element_desc` describes:
+/// for every vertex, where its element is on the unit sphere, and the element's index.
+fn element_buffer(device: &wgpu::Device, elements: &[[f32; 4]]) -> wgpu::Buffer {
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Element Buffer"),
+        contents: bytemuck::cast_slice(elements),
+        usage: wgpu::BufferUsage::VERTEX,
+    })
+}
+
+/// the layout of an element buffer, the second vertex buffer of the pipeline.
+fn element_desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+    wgpu::VertexBufferLayout {
+        array_stride: std::mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+        step_mode: wgpu::InputStepMode::Vertex,
+        attributes: &[wgpu::VertexAttribute {
+            offset: 0,
+            shader_location: 3,
+            format: wgpu::VertexFormat::Float4,
+        }],
+    }
+}
+
+/// the `ColorMapper` `preset` asks for, or the latitude bands if there is none by that name.
+fn colors(preset: &Preset) -> Box<dyn color::ColorMapper> {
+    color::builtin(&preset.colors).unwrap_or_else(|| {
+        log::warn!("unknown colors '{}', using 'bands'", preset.colors);
+        Box::new(color::LatitudeBands)
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn test_audio_uniforms_layout() {
+    // the `Audio` block of the vertex shader is four vec4 bands, the beat, four vec4 levels,
+    // the features, the mapper and a palette of eight colors
+    assert_eq!(std::mem::size_of::<AudioUniforms>(), 19 * 16);
+}
+#[test]
+fn test_audio_features() {
+    let mut audio = AudioUniforms::default();
+    audio.levels[3] = 0.5;
+    audio.features = [0.1, 0.2, 0.3, 0.4];
+    let features = audio.features();
+    assert_eq!(features.bands[3], 0.5);
+    assert_eq!(features.treble, 0.3);
+    assert_eq!(features.loudness(), 0.4);
 }
@@ -0,0 +1,153 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use super::{element_buffer, light::Light, mesh, vertex::Vertex, AudioUniforms, Uniforms};
+use wgpu::util::DeviceExt;
+
+/// top of the bar, and its depth behind the part that has been played.
+const TOP: f32 = -0.98;
+const BACK: f32 = 0.01;
+const TRACK_COLOR: [f32; 3] = [0.5, 0.5, 0.5];
+const PLAYED_COLOR: [f32; 3] = [0.95, 0.45, 0.1];
+const PAUSED_COLOR: [f32; 3] = [0.3, 0.6, 0.95];
+
+/// `ProgressBar` is a thin bar along the bottom of the image,
+/// filled from the left as far as the track has been played.
+///
+/// It is drawn with the same pipeline as the sphere, straight in clip space:
+/// its uniforms leave the vertices where they are, its audio keeps the colors of the vertices
+/// as if the sphere were fading out of them, and its light leaves the colors as they are.
+/// The bar is hidden until it is first given a position.
+pub struct ProgressBar {
+    mesh: mesh::GpuMesh,
+    element_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    visible: bool,
+}
+
+impl ProgressBar {
+    /// Create the bar on `device`, with uniforms laid out by `layout`.
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout) -> Self {
+        let uniforms = Uniforms {
+            eye: [0.0, 0.0, 1.0, 1.0],
+            ..Uniforms::new()
+        };
+        let buffer = |label, contents: &[u8]| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage: wgpu::BufferUsage::UNIFORM,
+            })
+        };
+        let uniform_buffer = buffer("Overlay Uniform Buffer", bytemuck::cast_slice(&[uniforms]));
+        let audio = AudioUniforms {
+            beat: [0.0, 0.0, 0.0, 1.0],
+            ..AudioUniforms::default()
+        };
+        let audio_buffer = buffer("Overlay Audio Buffer", bytemuck::cast_slice(&[audio]));
+        let light_buffer = buffer(
+            "Overlay Light Buffer",
+            bytemuck::cast_slice(&[Light::flat().uniforms()]),
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: audio_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: light_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("overlay_bind_group"),
+        });
+        let mesh = mesh::Mesh::new(
+            vertices(0.0, false),
+            vec![0, 1, 2, 0, 2, 3, 4, 5, 6, 4, 6, 7],
+        )
+        .unwrap()
+        .upload(device);
+        Self {
+            mesh,
+            element_buffer: element_buffer(device, &[[0.0; 4]; 8]),
+            bind_group,
+            visible: false,
+        }
+    }
+
+    /// Fill the bar up to `fraction` of its length, in another color while `paused`,
+    /// and show it from now on.
+    pub fn set(&mut self, queue: &wgpu::Queue, fraction: f32, paused: bool) {
+        self.mesh.write_vertices(queue, &vertices(fraction, paused));
+        self.visible = true;
+    }
+
+    pub fn hide(&mut self) {
+        self.visible = false;
+    }
+
+    /// Draw the bar into `render_pass`, if it is shown, over whatever is already there.
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if self.visible {
+            render_pass.set_bind_group(0, &self.bind_group, &[]);
+            render_pass.set_vertex_buffer(1, self.element_buffer.slice(..));
+            self.mesh.draw(render_pass);
+        }
+    }
+}
+
+/// The two quads of the bar in clip space, counter-clockwise like the sphere:
+/// the whole track behind, then the part that has been played in front of it.
+fn vertices(fraction: f32, paused: bool) -> Vec<Vertex> {
+    let end = -1.0 + 2.0 * fraction.clamp(0.0, 1.0);
+    let played = if paused { PAUSED_COLOR } else { PLAYED_COLOR };
+    let quad = |right: f32, depth: f32, color: [f32; 3]| {
+        let corners = [[-1.0, -1.0], [right, -1.0], [right, TOP], [-1.0, TOP]];
+        corners
+            .iter()
+            .map(|[x, y]| Vertex {
+                position: [*x, *y, depth],
+                color,
+                normal: [0.0, 0.0, 1.0],
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut vertices = quad(1.0, BACK, TRACK_COLOR);
+    vertices.extend(quad(end, 0.0, played));
+    vertices
+}
+
+#[cfg(test)]
+#[test]
+fn test_vertices() {
+    let half = vertices(0.5, false);
+    assert_eq!(half.len(), 8);
+    assert_eq!(half[1].position, [1.0, -1.0, BACK]);
+    assert_eq!(half[5].position, [0.0, -1.0, 0.0]);
+    assert_eq!(half[6].color, PLAYED_COLOR);
+    // the bar never runs past either end
+    assert_eq!(vertices(1.5, true)[5].position[0], 1.0);
+    assert_eq!(vertices(-0.5, true)[5].position[0], -1.0);
+    assert_eq!(vertices(0.0, true)[4].color, PAUSED_COLOR);
+}
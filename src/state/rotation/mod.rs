@@ -0,0 +1,115 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use cgmath::{InnerSpace, Vector3};
+use std::time::Duration;
+
+/// `Rotation` describes how the sphere spins: around `axis`, at `speed` degrees per second,
+/// or, when `bars` is set and the tempo is known, once every `bars` bars of the music.
+///
+/// # Examples
+/// ```
+/// # use kartina::state::rotation::Rotation;
+/// let rotation = Rotation { axis: [0.0, 0.0, 1.0], speed: 90.0, bars: Some(4.0) };
+/// // one revolution per 4 bars of 4 beats at 120 bpm is 45 degrees per second
+/// assert!(rotation.speed(Some(120.0)) == 45.0);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rotation {
+    pub axis: [f32; 3],
+    pub speed: f32,
+    pub bars: Option<f32>,
+}
+
+impl Rotation {
+    pub const BEATS_PER_BAR: f32 = 4.0;
+
+    /// degrees per second the sphere turns by when the tempo is `bpm`.
+    /// Without a tempo, or when not locked to it, this is `speed`.
+    pub fn speed(&self, bpm: Option<f32>) -> f32 {
+        match (self.bars, bpm) {
+            (Some(bars), Some(bpm)) if bars > 0.0 && bpm > 0.0 => {
+                let seconds = bars * Self::BEATS_PER_BAR * 60.0 / bpm;
+                360.0 / seconds
+            }
+            _ => self.speed,
+        }
+    }
+
+    /// degrees the sphere turns by in `elapsed` time at tempo `bpm`.
+    pub fn angle(&self, elapsed: Duration, bpm: Option<f32>) -> cgmath::Deg<f32> {
+        cgmath::Deg(self.speed(bpm) * elapsed.as_secs_f32())
+    }
+
+    /// the axis as a unit vector; an axis of zero length turns around z.
+    pub fn axis(&self) -> Vector3<f32> {
+        let axis = Vector3::from(self.axis);
+        if axis.magnitude() > f32::EPSILON {
+            axis.normalize()
+        } else {
+            Vector3::unit_z()
+        }
+    }
+}
+
+impl Default for Rotation {
+    /// around z at 120 degrees per second, as kartina always has at 60 frames per second.
+    fn default() -> Self {
+        Self {
+            axis: [0.0, 0.0, 1.0],
+            speed: 120.0,
+            bars: None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_speed() {
+    let rotation = Rotation::default();
+    assert_eq!(rotation.speed(Some(128.0)), 120.0);
+    // a frame at 60Hz turns by as much as a frame at 144Hz, relative to its length
+    let slow = rotation.angle(Duration::from_secs_f32(1.0 / 60.0), None);
+    let fast = rotation.angle(Duration::from_secs_f32(1.0 / 144.0), None);
+    assert!((slow.0 - 2.0).abs() < 1e-4);
+    assert!((fast.0 * 144.0 / 60.0 - 2.0).abs() < 1e-4);
+}
+#[test]
+fn test_tempo_lock() {
+    let rotation = Rotation {
+        bars: Some(4.0),
+        ..Rotation::default()
+    };
+    // 16 beats at 120bpm take 8 seconds
+    assert_eq!(rotation.speed(Some(120.0)), 45.0);
+    // until the tempo is known, the speed is used
+    assert_eq!(rotation.speed(None), 120.0);
+}
+#[test]
+fn test_axis() {
+    let rotation = Rotation {
+        axis: [0.0, 3.0, 0.0],
+        ..Rotation::default()
+    };
+    assert_eq!(rotation.axis(), Vector3::unit_y());
+    let rotation = Rotation {
+        axis: [0.0; 3],
+        ..Rotation::default()
+    };
+    assert_eq!(rotation.axis(), Vector3::unit_z());
+}
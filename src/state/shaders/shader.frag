@@ -1,8 +1,38 @@
 #version 450
 
 layout(location=0) in vec3 v_color;
+layout(location=1) in vec3 v_normal;
+layout(location=2) in vec3 v_position;
+
 layout(location=0) out vec4 f_color;
 
+layout(set=0, binding=0)
+uniform Uniforms {
+    mat4 u_view_proj;
+    mat4 u_model;
+    vec4 u_eye;
+};
+
+// see `LightUniforms`
+layout(set=0, binding=2)
+uniform Light {
+    vec4 u_direction; // direction the light travels in, ambient share
+    vec4 u_light;     // color, intensity
+    vec4 u_specular;  // strength, shininess
+};
+
 void main() {
-    f_color = vec4(v_color, 1.0);
+    vec3 normal = normalize(v_normal);
+    vec3 to_light = -u_direction.xyz;
+    vec3 light = u_light.rgb * u_light.a;
+
+    float diffuse = max(dot(normal, to_light), 0.0);
+    // Blinn-Phong: the highlight is strongest where the normal lies halfway
+    // between the directions to the light and to the camera
+    vec3 to_eye = normalize(u_eye.xyz - v_position);
+    vec3 halfway = normalize(to_light + to_eye);
+    float specular = u_specular.x * pow(max(dot(normal, halfway), 0.0), u_specular.y);
+
+    vec3 color = v_color * (u_direction.w + diffuse * light) + specular * light;
+    f_color = vec4(color, 1.0);
 }
@@ -2,15 +2,126 @@
 
 layout(location=0) in vec3 a_position;
 layout(location=1) in vec3 a_color;
+layout(location=2) in vec3 a_normal;
+// where the element the vertex belongs to is on the unit sphere, and its index, see `color::Element`
+layout(location=3) in vec4 a_element;
 
 layout(location=0) out vec3 v_color;
+layout(location=1) out vec3 v_normal;
+layout(location=2) out vec3 v_position;
 
 layout(set=0, binding=0)
 uniform Uniforms {
     mat4 u_view_proj;
+    mat4 u_model;
+    vec4 u_eye;
 };
 
+// the music and the color mapper, see `AudioUniforms`
+layout(set=0, binding=1)
+uniform Audio {
+    vec4 u_bands[4];   // smoothed levels of the 16 frequency bands, low to high
+    vec4 u_beat;       // pulse, color shift, displacement, share of the colors of the last track
+    vec4 u_levels[4];  // levels of the bands in the frame being heard
+    vec4 u_features;   // bass, mid, treble, loudness
+    uvec4 u_mapper;    // mapper, colors in the palette, number of bands
+    vec4 u_palette[8];
+};
+
+const float PI = 3.14159265358979;
+
+// level of the spectrum at `t` between 0.0 (lowest band) and 1.0 (highest band),
+// blended between the two nearest bands so that the surface stays smooth
+float spectrum(float t) {
+    float position = clamp(t, 0.0, 1.0) * 15.0;
+    int low = int(floor(position));
+    int high = min(low + 1, 15);
+    return mix(u_bands[low / 4][low % 4], u_bands[high / 4][high % 4], fract(position));
+}
+
+// level of `band` in the frame being heard
+float level(int band) {
+    return u_levels[band / 4][band % 4];
+}
+
+// the band assigned to `t` between 0.0 and 1.0, rounded like `color::band`
+int band(float t) {
+    return int(floor(clamp(t, 0.0, 1.0) * float(u_mapper.z - 1u) + 0.5));
+}
+
+// the color of `hue`, `saturation` and `value`, see `color::hsv`
+vec3 hsv(float hue, float saturation, float value) {
+    float h = fract(hue) * 6.0;
+    float c = value * saturation;
+    float x = c * (1.0 - abs(mod(h, 2.0) - 1.0));
+    int sector = int(h);
+    vec3 rgb = vec3(c, 0.0, x);
+    if (sector == 0) {
+        rgb = vec3(c, x, 0.0);
+    } else if (sector == 1) {
+        rgb = vec3(x, c, 0.0);
+    } else if (sector == 2) {
+        rgb = vec3(0.0, c, x);
+    } else if (sector == 3) {
+        rgb = vec3(0.0, x, c);
+    } else if (sector == 4) {
+        rgb = vec3(x, 0.0, c);
+    }
+    return rgb + vec3(value - c);
+}
+
+// the color the mapper picked by `u_mapper` gives the element at `position` with index `id`,
+// the same as the `ColorMapper` does on the CPU
+vec3 map(vec3 position, int id) {
+    float latitude = position.z;
+    int bands = int(u_mapper.z);
+    if (u_mapper.x == 1u) {
+        // `SpectrumHue`
+        int b = band(fract(atan(position.y, position.x) / (2.0 * PI)));
+        float hue = 0.8 * float(b) / float(bands - 1);
+        return hsv(hue, 1.0, 0.15 + 0.85 * level(b));
+    } else if (u_mapper.x == 2u) {
+        // `EnergyGradient`
+        float t = min(2.0 * u_features.w, 1.0);
+        float shade = 0.5 + 0.25 * (latitude + 1.0);
+        return mix(vec3(0.1, 0.2, 0.8), vec3(1.0, 0.3, 0.1), t) * shade;
+    } else if (u_mapper.x == 3u) {
+        // `Palette`
+        float l = clamp(level(id % bands), 0.0, 1.0);
+        int last = int(u_mapper.y) - 1;
+        return u_palette[int(floor(l * float(last) + 0.5))].rgb;
+    }
+    // `LatitudeBands`
+    float brightness = 0.25 + min(u_features.w, 0.5) + 0.5 * level(band((latitude + 1.0) / 2.0));
+    return u_features.xyz * brightness;
+}
+
 void main() {
-    v_color = a_color;
-    gl_Position = u_view_proj * vec4(a_position, 1.0);
+    // the low bands push out the bottom of the sphere, the high bands the top,
+    // and the spectrum is also wrapped around it, mirrored so that it meets itself seamlessly
+    float latitude = (a_normal.z + 1.0) / 2.0;
+    float around = 0.5 - 0.5 * cos(atan(a_normal.y, a_normal.x));
+    // the longitude is meaningless at the poles, so it fades out towards them
+    float width = length(a_normal.xy);
+    float level = mix(spectrum(latitude), spectrum(around), 0.5 * width);
+    vec3 position = a_position + a_normal * length(a_position) * u_beat.z * level;
+
+    // right after a change of track, the colors of the last track, kept in the vertices,
+    // blend into those of the new one
+    vec3 color = mix(map(a_element.xyz, int(a_element.w)), a_color, u_beat.w);
+    // strong beats rotate the color channels
+    int shift = int(u_beat.y);
+    if (shift == 1) {
+        color = color.yzx;
+    } else if (shift == 2) {
+        color = color.zxy;
+    }
+    // and every beat flashes the sphere a little brighter
+    v_color = color * (1.0 + 0.5 * u_beat.x);
+
+    // the sphere is only ever rotated and scaled evenly, so its normals turn with it
+    vec4 world = u_model * vec4(position, 1.0);
+    v_normal = mat3(u_model) * a_normal;
+    v_position = world.xyz;
+    gl_Position = u_view_proj * world;
 }
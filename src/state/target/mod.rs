@@ -0,0 +1,175 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use super::texture::Texture;
+use std::iter;
+
+/// format of the offscreen images, which is also the layout `image::RgbaImage` expects.
+const OFFSCREEN_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// Where `State` draws the sphere: the swap chain of a window,
+/// or a texture that is copied back to the CPU once it has been drawn.
+pub enum RenderTarget {
+    Window {
+        surface: wgpu::Surface,
+        sc_desc: wgpu::SwapChainDescriptor,
+        swap_chain: wgpu::SwapChain,
+    },
+    Offscreen {
+        texture: Texture,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl RenderTarget {
+    /// Create a swap chain of `width` by `height` pixels presenting to `surface`.
+    pub fn window(
+        device: &wgpu::Device,
+        adapter: &wgpu::Adapter,
+        surface: wgpu::Surface,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let sc_desc = wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+            format: adapter.get_swap_chain_preferred_format(&surface),
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+        };
+        let swap_chain = device.create_swap_chain(&surface, &sc_desc);
+        RenderTarget::Window {
+            surface,
+            sc_desc,
+            swap_chain,
+        }
+    }
+
+    /// Create a texture of `width` by `height` pixels to draw into instead of a window.
+    pub fn offscreen(device: &wgpu::Device, width: u32, height: u32) -> Self {
+        RenderTarget::Offscreen {
+            texture: Texture::create_render_target(
+                device,
+                width,
+                height,
+                OFFSCREEN_FORMAT,
+                "offscreen_texture",
+            ),
+            width,
+            height,
+        }
+    }
+
+    /// width and height of the images drawn into the target.
+    pub fn size(&self) -> (u32, u32) {
+        match self {
+            RenderTarget::Window { sc_desc, .. } => (sc_desc.width, sc_desc.height),
+            RenderTarget::Offscreen { width, height, .. } => (*width, *height),
+        }
+    }
+
+    /// format of the color attachment, which the render pipeline has to be created with.
+    pub fn format(&self) -> wgpu::TextureFormat {
+        match self {
+            RenderTarget::Window { sc_desc, .. } => sc_desc.format,
+            RenderTarget::Offscreen { .. } => OFFSCREEN_FORMAT,
+        }
+    }
+
+    /// Recreate the swap chain, or the texture, at `width` by `height` pixels.
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        match self {
+            RenderTarget::Window {
+                surface,
+                sc_desc,
+                swap_chain,
+            } => {
+                sc_desc.width = width;
+                sc_desc.height = height;
+                *swap_chain = device.create_swap_chain(surface, sc_desc);
+            }
+            RenderTarget::Offscreen { .. } => *self = Self::offscreen(device, width, height),
+        }
+    }
+
+    /// Copy the last image drawn into an offscreen target back from the GPU.
+    /// A window presents its images instead of keeping them, so for a window this returns `None`.
+    pub async fn read_image(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Option<image::RgbaImage> {
+        let (texture, width, height) = match self {
+            RenderTarget::Window { .. } => return None,
+            RenderTarget::Offscreen {
+                texture,
+                width,
+                height,
+            } => (texture, *width, *height),
+        };
+        // rows of a texture copy have to start on a 256 byte boundary,
+        // so each row of the buffer may end in some padding
+        let unpadded = 4 * width;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded = (unpadded + align - 1) / align * align;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Capture Buffer"),
+            size: (padded * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Capture Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: &texture.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::BufferCopyView {
+                buffer: &buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: padded,
+                    rows_per_image: height,
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+        );
+        queue.submit(iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapped = slice.map_async(wgpu::MapMode::Read);
+        // nothing else drives the device, so it has to be waited on here
+        device.poll(wgpu::Maintain::Wait);
+        mapped.await.ok()?;
+        let pixels = slice
+            .get_mapped_range()
+            .chunks(padded as usize)
+            .flat_map(|row| row[..unpadded as usize].to_vec())
+            .collect();
+        buffer.unmap();
+        image::RgbaImage::from_raw(width, height, pixels)
+    }
+}
@@ -16,6 +16,7 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use super::mesh::Mesh;
 use std::f32::consts::PI;
 
 /// structure to store `Vertex` information. Use of #[repr(C)]
@@ -29,6 +30,8 @@ use std::f32::consts::PI;
 pub struct Vertex {
     pub position: [f32; 3],
     pub color: [f32; 3],
+    /// unit vector pointing straight out of the surface, used for lighting.
+    pub normal: [f32; 3],
 }
 
 impl Vertex {
@@ -36,7 +39,8 @@ impl Vertex {
     ///
     /// # Examples
     /// ```
-    /// let mut v = Vertex{ [1.0, 2.0, 3.0], [0.5, 0.5, 0.5] };
+    /// # use kartina::Vertex;
+    /// let mut v = Vertex { position: [1.0, 2.0, 3.0], color: [0.5, 0.5, 0.5], normal: [0.0, 0.0, 1.0] };
     /// v.change_color([2.0, 3.0, 5.0]);
     /// assert!(v.color == [2.0, 3.0, 5.0]);
     /// ```
@@ -66,56 +70,120 @@ impl Vertex {
                     shader_location: 1,
                     format: wgpu::VertexFormat::Float3,
                 },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 6]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float3,
+                },
             ],
         }
     }
+}
 
-    /// Returns a vector of vertices that correspond to a sphere
-    /// of radius `r`, centered at the origin.
-    ///
-    /// The definition of a sphere is a 3d closed surface wher every point on
-    /// the sphere is the same distance (radius) from an arbitrary center point.
-    ///
-    /// The equation of a sphere at the origin is x^2 + y^2 + z^2.
-    /// A sphere is drawn by first, sampling a limited amount of points from the sphere.
-    ///
-    /// The sphere itself is then divided up vertically and horizontally, creating
-    /// a cross section of the sphere where the horizontal sections are composed of
-    /// individual `sectors` and the vertical sections are composed of individual `stacks`.
-    /// Together, the sectors and stacks compose the surface of the sphere.
-    ///
-    /// This function specifically generates a sphere centered at the origin
-    /// with radius `r`. The sphere consists of 18 stacks, and 36 total sectors.
-    /// For more information, reference `OpenGL Sphere` in the references section of the `README.md`
-    ///
-    /// # Examples
-    /// ```
-    /// let mut vbo = sphere_vertices(4.0);
-    /// vbo[3].change_color([1.0,2.0,3.0])
-    /// assert!(vbo[3].color == [1.0,2.0,3.0]);
-    /// ```
-    pub fn sphere_vertices(r: f32) -> Vec<Vertex> {
+/// `SphereBuilder` generates the vertices and indices of a sphere
+/// of a given `radius`, centered at the origin.
+///
+/// The definition of a sphere is a 3d closed surface wher every point on
+/// the sphere is the same distance (radius) from an arbitrary center point.
+///
+/// The equation of a sphere at the origin is x^2 + y^2 + z^2 = r^2.
+/// A sphere is drawn by first, sampling a limited amount of points from the sphere.
+///
+/// The sphere itself is then divided up vertically and horizontally, creating
+/// a cross section of the sphere where the horizontal sections are composed of
+/// individual `sectors` and the vertical sections are composed of individual `stacks`.
+/// Together, the sectors and stacks compose the surface of the sphere.
+/// More stacks and sectors give a smoother sphere, at the cost of more vertices.
+/// For more information, reference `OpenGL Sphere` in the references section of the `README.md`
+///
+/// # Examples
+/// ```
+/// # use kartina::SphereBuilder;
+/// let mesh = SphereBuilder::new().radius(2.0).stacks(32).sectors(64).build();
+/// assert!(mesh.vertices().len() == 33 * 65);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SphereBuilder {
+    radius: f32,
+    stacks: u32,
+    sectors: u32,
+}
+
+impl SphereBuilder {
+    /// fewest stacks and sectors that still enclose a volume.
+    pub const MIN_STACKS: u32 = 2;
+    pub const MIN_SECTORS: u32 = 3;
+
+    /// A unit sphere with 18 stacks and 36 sectors.
+    pub fn new() -> Self {
+        Self {
+            radius: 1.0,
+            stacks: 18,
+            sectors: 36,
+        }
+    }
+
+    /// set the distance from the center to every vertex.
+    pub fn radius(mut self, radius: f32) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    /// set the number of horizontal slices, from pole to pole.
+    /// Values below `MIN_STACKS` are raised to it.
+    pub fn stacks(mut self, stacks: u32) -> Self {
+        self.stacks = stacks.max(Self::MIN_STACKS);
+        self
+    }
+
+    /// set the number of vertical slices around the sphere.
+    /// Values below `MIN_SECTORS` are raised to it.
+    pub fn sectors(mut self, sectors: u32) -> Self {
+        self.sectors = sectors.max(Self::MIN_SECTORS);
+        self
+    }
+
+    /// Generate the mesh of the sphere: its vertices and the triangles connecting them.
+    pub fn build(&self) -> Mesh {
+        // every index points at a vertex of the stacks above and below it
+        Mesh::new(self.vertices(), self.indices()).unwrap()
+    }
+
+    /// Returns a vector of `(stacks + 1) * (sectors + 1)` vertices, one stack at a time
+    /// from the north pole to the south pole. The first and last vertex of every stack
+    /// are in the same place, so that every sector has its own pair of edges.
+    fn vertices(&self) -> Vec<Vertex> {
         // vector to contain all vertices which will be returned
-        let mut vertices = Vec::new();
+        let mut vertices = Vec::with_capacity(((self.stacks + 1) * (self.sectors + 1)) as usize);
 
         // vertex position
         let (mut x, mut y, mut z, mut xy): (f32, f32, f32, f32);
+        let (mut z_unit, mut xy_unit): (f32, f32);
         let (mut stack_angle, mut sector_angle): (f32, f32);
-        let (stack_step, sector_step): (f32, f32) = (PI / 18.0, 2.0 * PI / 36.0);
+        let (stack_step, sector_step): (f32, f32) =
+            (PI / self.stacks as f32, 2.0 * PI / self.sectors as f32);
 
-        for i in 0..=18 {
+        for i in 0..=self.stacks {
             stack_angle = PI / 2.0 - i as f32 * stack_step;
 
-            xy = (r / 10.0) * stack_angle.cos();
-            z = (r / 10.0) * stack_angle.sin();
-            for j in 0..=36 {
+            xy_unit = stack_angle.cos();
+            z_unit = stack_angle.sin();
+            xy = self.radius * xy_unit;
+            z = self.radius * z_unit;
+            for j in 0..=self.sectors {
                 sector_angle = j as f32 * sector_step;
 
                 x = xy * sector_angle.cos();
                 y = xy * sector_angle.sin();
+                // the normal of a sphere at the origin points the same way as the position
                 vertices.push(Vertex {
                     position: [x, y, z],
                     color: [0.0, 0.0, 0.0],
+                    normal: [
+                        xy_unit * sector_angle.cos(),
+                        xy_unit * sector_angle.sin(),
+                        z_unit,
+                    ],
                 });
             }
         }
@@ -132,54 +200,60 @@ impl Vertex {
     /// so the index array would then look like: [0,1,2]
     /// The vertices are then connected using the index buffer in a counter-clockwise order:
     ///
+    /// ```text
     ///             top
     ///             /\
     ///            /  \
     ///           /    \
     ///      left/______\right
-    ///
-    /// # Examples
     /// ```
-    /// let ibo = sphere_indices();
-    /// ```
-    pub fn sphere_indices() -> Vec<u32> {
-        let mut indices = Vec::new();
+    ///
+    /// The stacks touching the poles are made of one triangle per sector, the others of two.
+    fn indices(&self) -> Vec<u32> {
+        let mut indices = Vec::with_capacity((6 * self.sectors * (self.stacks - 1)) as usize);
 
-        for i in 0..18 {
-            let mut k1 = i * 37; // # of sectors + 1
-            let mut k2 = k1 + 37;
-            for _j in 0..36 {
+        for i in 0..self.stacks {
+            for j in 0..self.sectors {
+                let k1 = i * (self.sectors + 1) + j;
+                let k2 = k1 + self.sectors + 1;
                 if i != 0 {
                     indices.push(k1);
                     indices.push(k2);
                     indices.push(k1 + 1);
                 }
-                if i != 17 {
-                    // # of stacks - 1
+                if i != self.stacks - 1 {
                     indices.push(k1 + 1);
                     indices.push(k2);
                     indices.push(k2 + 1);
                 }
-                k1 += 1;
-                k2 += 1;
             }
         }
         indices
     }
 }
 
+impl Default for SphereBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 #[test]
 fn test_change_color() {
     let position = [1.0, 2.0, 3.0];
     let color = position;
-    let mut v = Vertex { position, color };
+    let mut v = Vertex {
+        position,
+        color,
+        normal: [0.0, 0.0, 1.0],
+    };
     v.change_color([4.0, 7.5, 9.0]);
     assert!([4.0, 7.5, 9.0] == v.color);
 }
 #[test]
 fn test_vertices() {
-    let vertices = Vertex::sphere_vertices(1.0);
+    let vertices = SphereBuilder::new().radius(0.1).vertices();
     let mut test = Vec::new();
     let (mut x, mut y, mut z, mut xy): (f32, f32, f32, f32);
     let (mut stack_angle, mut sector_angle): (f32, f32);
@@ -198,17 +272,24 @@ fn test_vertices() {
             test.push(Vertex {
                 position: [x, y, z],
                 color: [0.0, 0.0, 0.0],
+                normal: [x * 10.0, y * 10.0, z * 10.0],
             });
         }
     }
+    assert_eq!(vertices.len(), test.len());
     for (index, vertex) in vertices.iter().enumerate() {
         assert!(vertex.color == test[index].color);
         assert!(vertex.position == test[index].position);
+        for axis in 0..3 {
+            assert!((vertex.normal[axis] - test[index].normal[axis]).abs() < 1e-6);
+        }
     }
 }
 #[test]
+// the counters are kept as they were written against the original sphere, which this checks against
+#[allow(clippy::explicit_counter_loop)]
 fn test_indices() {
-    let indices = Vertex::sphere_indices();
+    let indices = SphereBuilder::new().indices();
     let mut test = Vec::new();
 
     for i in 0..18 {
@@ -231,3 +312,25 @@ fn test_indices() {
     }
     assert!(test == indices);
 }
+#[test]
+fn test_tessellation() {
+    for &(stacks, sectors) in &[(2, 3), (18, 36), (64, 128), (5, 7)] {
+        let builder = SphereBuilder::new()
+            .radius(3.0)
+            .stacks(stacks)
+            .sectors(sectors);
+        let (vertices, indices) = (builder.vertices(), builder.indices());
+        assert_eq!(vertices.len() as u32, (stacks + 1) * (sectors + 1));
+        assert_eq!(indices.len() as u32, 6 * sectors * (stacks - 1));
+        assert!(indices.iter().all(|&i| (i as usize) < vertices.len()));
+        for vertex in &vertices {
+            let [x, y, z] = vertex.position;
+            assert!(((x * x + y * y + z * z).sqrt() - 3.0).abs() < 1e-5);
+            let [x, y, z] = vertex.normal;
+            assert!(((x * x + y * y + z * z).sqrt() - 1.0).abs() < 1e-5);
+        }
+    }
+    // too few stacks or sectors to enclose anything
+    let mesh = SphereBuilder::new().stacks(0).sectors(1).build();
+    assert_eq!(mesh.vertices().len(), 3 * 4);
+}
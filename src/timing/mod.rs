@@ -0,0 +1,454 @@
+/*
+Kartina is a GPU shader that renders a sphere colored using decoded mp3 frame data.
+Copyright (C) 2021 Timothy Maloney
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::audio::{AudioSource, Frame};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A `Clock` reports the current presentation time,
+/// measured from the moment playback started.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// `Clock` driven by the audio output.
+/// It moves forward by the samples each frame holds as the output accepts that frame,
+/// so the presentation time is the position of what is being heard.
+#[derive(Clone, Default)]
+pub struct AudioClock {
+    played: Arc<Mutex<Timeline>>,
+}
+
+impl AudioClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// record that `frame` has been handed to the output.
+    pub fn advance(&self, frame: &Frame) {
+        self.played.lock().unwrap().consume(frame);
+    }
+}
+
+impl Clock for AudioClock {
+    fn now(&self) -> Duration {
+        self.played.lock().unwrap().now()
+    }
+}
+
+/// `Clock` that only moves when told to.
+/// Used to drive the scheduler deterministically, in tests and when exporting a video.
+///
+/// # Examples
+/// ```
+/// # use kartina::timing::{Clock, SimulatedClock};
+/// # use std::time::Duration;
+/// let clock = SimulatedClock::new();
+/// clock.advance(Duration::from_millis(10));
+/// assert!(clock.now() == Duration::from_millis(10));
+/// ```
+#[derive(Default)]
+pub struct SimulatedClock {
+    now: std::cell::Cell<Duration>,
+}
+
+impl SimulatedClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// move the clock forward by `step`.
+    pub fn advance(&self, step: Duration) {
+        self.now.set(self.now.get() + step);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+/// Position within a decoded stream, kept as a count of samples (per channel)
+/// rather than as a `Duration`, so that no rounding error builds up over a song.
+#[derive(Clone, Copy, Debug, Default)]
+struct Timeline {
+    // time at which the current sample rate took effect
+    base: Duration,
+    samples: u64,
+    sample_rate: u32,
+}
+
+impl Timeline {
+    fn now(&self) -> Duration {
+        if self.sample_rate == 0 {
+            return self.base;
+        }
+        let rate = self.sample_rate as u64;
+        let secs = self.samples / rate;
+        let nanos = (self.samples % rate) * 1_000_000_000 / rate;
+        self.base + Duration::new(secs, nanos as u32)
+    }
+
+    /// move the timeline past `frame`, returning the time at which it ends.
+    fn consume(&mut self, frame: &Frame) -> Duration {
+        if frame.sample_rate != self.sample_rate {
+            self.base = self.now();
+            self.samples = 0;
+            self.sample_rate = frame.sample_rate;
+        }
+        self.samples += frame.samples() as u64;
+        self.now()
+    }
+}
+
+/// A frame that has come due, along with where it starts within its track.
+#[derive(Debug)]
+pub struct Due {
+    pub frame: Frame,
+    pub position: Duration,
+}
+
+/// Result of asking the scheduler which frame belongs on screen.
+#[derive(Debug)]
+pub enum Tick {
+    /// new frames have come due, oldest first. The last one should be handed to `State::input`;
+    /// those before it were passed over on screen, but still belong to the stream,
+    /// e.g. for finding its beats.
+    Present(Vec<Due>),
+    /// the frame that is already on screen is still current.
+    Repeat,
+    /// the stream has ended and the last frame has run its course.
+    End,
+}
+
+/// `FrameScheduler` pairs an `AudioSource` with a presentation time,
+/// so that the frame being drawn is the frame being heard.
+///
+/// Each frame covers the span `[start, end)`, where `start` is when the source says
+/// the frame is heard, or else follows on from the frame before, and `end` is computed
+/// from its number of samples and its `sample_rate`. A source that skips frames
+/// therefore leaves a gap rather than pulling everything after it early.
+/// When the clock has moved past several frames between two ticks,
+/// the frames in the past are dropped; when it has not yet reached the end
+/// of the frame on screen, that frame is repeated.
+pub struct FrameScheduler<S> {
+    source: S,
+    timeline: Timeline,
+    // next frame that has been decoded but not presented, with its start and end
+    pending: Option<(Duration, Duration, Due)>,
+    // end of the most recently presented frame
+    shown_until: Duration,
+    eof: bool,
+    dropped: u64,
+    repeated: u64,
+}
+
+impl<S: AudioSource> FrameScheduler<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            timeline: Timeline::default(),
+            pending: None,
+            shown_until: Duration::from_secs(0),
+            eof: false,
+            dropped: 0,
+            repeated: 0,
+        }
+    }
+
+    /// Pick the frame that should be on screen at presentation time `now`.
+    pub fn tick(&mut self, now: Duration) -> io::Result<Tick> {
+        let mut due = Vec::new();
+        loop {
+            if self.pending.is_none() && !self.eof {
+                // a live source may not have captured anything yet,
+                // which is no reason to hold up whatever is drawing the frames
+                if !self.source.is_ready() {
+                    break;
+                }
+                match self.source.next_frame()? {
+                    Some(frame) => {
+                        if let Some(start) = self.source.start() {
+                            self.timeline = Timeline {
+                                base: start,
+                                ..Timeline::default()
+                            };
+                        }
+                        let start = self.timeline.now();
+                        let end = self.timeline.consume(&frame);
+                        let position = self.source.position().unwrap_or(start);
+                        self.pending = Some((start, end, Due { frame, position }));
+                    }
+                    None => self.eof = true,
+                }
+            }
+            match self.pending.take() {
+                Some((start, end, frame)) if start <= now => {
+                    self.shown_until = end;
+                    if !due.is_empty() {
+                        self.dropped += 1;
+                    }
+                    due.push(frame);
+                    if now < end {
+                        break;
+                    }
+                }
+                pending => {
+                    self.pending = pending;
+                    break;
+                }
+            }
+        }
+        match due {
+            due if !due.is_empty() => Ok(Tick::Present(due)),
+            _ if self.eof && self.pending.is_none() && now >= self.shown_until => Ok(Tick::End),
+            _ => {
+                self.repeated += 1;
+                Ok(Tick::Repeat)
+            }
+        }
+    }
+
+    /// Start over at presentation time `now`, e.g. after the source was seeked:
+    /// the next frame read from the source is due right away, and those after it follow on.
+    pub fn restart(&mut self, now: Duration) {
+        self.pending = None;
+        self.timeline = Timeline {
+            base: now,
+            ..Timeline::default()
+        };
+        self.shown_until = now;
+    }
+
+    /// the source the frames are read from.
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// how far into the stream the source has been decoded.
+    pub fn position(&self) -> Duration {
+        self.timeline.now()
+    }
+
+    /// number of frames that were skipped because the clock had already passed them.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// number of ticks on which the frame on screen was kept.
+    pub fn repeated(&self) -> u64 {
+        self.repeated
+    }
+}
+
+#[cfg(test)]
+use std::collections::VecDeque;
+
+/// `AudioSource` over a fixed list of frames, for testing.
+#[cfg(test)]
+struct Frames(VecDeque<Frame>);
+
+#[cfg(test)]
+impl AudioSource for Frames {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        Ok(self.0.pop_front())
+    }
+}
+
+/// `count` stereo frames of 1152 samples at 44.1kHz,
+/// each filled with its own index so they can be told apart.
+#[cfg(test)]
+fn frames(count: usize) -> Frames {
+    Frames(
+        (0..count)
+            .map(|i| Frame {
+                data: vec![i as i16; 1152 * 2],
+                sample_rate: 44100,
+                channels: 2,
+            })
+            .collect(),
+    )
+}
+
+/// `AudioSource` over a fixed list of frames, each with when it is heard, for testing.
+#[cfg(test)]
+struct Stamped(VecDeque<(Duration, Frame)>, Duration);
+
+#[cfg(test)]
+impl AudioSource for Stamped {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        Ok(self.0.pop_front().map(|(start, frame)| {
+            self.1 = start;
+            frame
+        }))
+    }
+
+    fn start(&self) -> Option<Duration> {
+        Some(self.1)
+    }
+}
+
+/// `AudioSource` over a fixed list of frames that only hands them out once it is ready,
+/// like a device that has not captured anything yet, for testing.
+#[cfg(test)]
+struct Live(Frames, bool);
+
+#[cfg(test)]
+impl AudioSource for Live {
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        assert!(self.1, "waited on a source that was not ready");
+        self.0.next_frame()
+    }
+
+    fn is_ready(&mut self) -> bool {
+        self.1
+    }
+}
+
+#[cfg(test)]
+fn presented(tick: Tick) -> Option<i16> {
+    match tick {
+        Tick::Present(due) => Some(due.last().unwrap().frame.data[0]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_timeline() {
+    let mut timeline = Timeline::default();
+    let frame = Frame {
+        data: vec![0; 44100 * 2],
+        sample_rate: 44100,
+        channels: 2,
+    };
+    assert_eq!(timeline.consume(&frame), Duration::from_secs(1));
+    let frame = Frame {
+        data: vec![0; 24000],
+        sample_rate: 48000,
+        channels: 1,
+    };
+    assert_eq!(timeline.consume(&frame), Duration::from_millis(1500));
+}
+#[test]
+fn test_audio_clock() {
+    let clock = AudioClock::new();
+    let shared = clock.clone();
+    for _ in 0..2 {
+        shared.advance(&Frame {
+            data: vec![0; 22050 * 2],
+            sample_rate: 44100,
+            channels: 2,
+        });
+    }
+    assert_eq!(clock.now(), Duration::from_secs(1));
+}
+#[test]
+fn test_simulated_clock() {
+    let clock = SimulatedClock::new();
+    assert_eq!(clock.now(), Duration::from_secs(0));
+    clock.advance(Duration::from_millis(250));
+    clock.advance(Duration::from_millis(250));
+    assert_eq!(clock.now(), Duration::from_millis(500));
+}
+#[test]
+fn test_repeat() {
+    let clock = SimulatedClock::new();
+    let mut scheduler = FrameScheduler::new(frames(4));
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(0));
+    // a frame lasts ~26ms, so a 5ms step stays on the same frame
+    clock.advance(Duration::from_millis(5));
+    assert!(matches!(scheduler.tick(clock.now()).unwrap(), Tick::Repeat));
+    assert_eq!(scheduler.repeated(), 1);
+    clock.advance(Duration::from_millis(25));
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(1));
+    assert_eq!(scheduler.dropped(), 0);
+}
+#[test]
+fn test_drop() {
+    let clock = SimulatedClock::new();
+    let mut scheduler = FrameScheduler::new(frames(8));
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(0));
+    // 100ms lands inside the fourth frame: 100 / 26.12 = 3.8
+    clock.advance(Duration::from_millis(100));
+    let due = match scheduler.tick(clock.now()).unwrap() {
+        Tick::Present(due) => due,
+        tick => panic!("{:?}", tick),
+    };
+    assert_eq!(scheduler.dropped(), 2);
+    // the frames passed over are handed out along with the one to draw
+    let starts: Vec<_> = due.iter().map(|due| due.position.as_micros()).collect();
+    assert_eq!(starts, [26_122, 52_244, 78_367]);
+    assert_eq!(due[2].frame.data[0], 3);
+}
+#[test]
+fn test_end() {
+    let clock = SimulatedClock::new();
+    let mut scheduler = FrameScheduler::new(frames(2));
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(0));
+    clock.advance(Duration::from_millis(30));
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(1));
+    // the last frame is still playing
+    assert!(matches!(scheduler.tick(clock.now()).unwrap(), Tick::Repeat));
+    clock.advance(Duration::from_millis(30));
+    assert!(matches!(scheduler.tick(clock.now()).unwrap(), Tick::End));
+    assert_eq!(scheduler.position(), Duration::from_nanos(52_244_897));
+}
+#[test]
+fn test_restart() {
+    let clock = SimulatedClock::new();
+    let mut scheduler = FrameScheduler::new(frames(8));
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(0));
+    clock.advance(Duration::from_millis(5));
+    // frame 1 was decoded while looking for the next one, and is dropped
+    assert!(matches!(scheduler.tick(clock.now()).unwrap(), Tick::Repeat));
+    scheduler.restart(clock.now());
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(2));
+    assert_eq!(
+        scheduler.position(),
+        Duration::from_nanos(5_000_000 + 26_122_448)
+    );
+}
+#[test]
+fn test_gap() {
+    let clock = SimulatedClock::new();
+    // the frames in between were skipped by the source
+    let frames = frames(2).0.into_iter();
+    let starts = [Duration::from_secs(0), Duration::from_secs(1)];
+    let source = Stamped(starts.iter().copied().zip(frames).collect(), starts[0]);
+    let mut scheduler = FrameScheduler::new(source);
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(0));
+    // the second frame is not due until it is heard
+    clock.advance(Duration::from_millis(500));
+    assert!(matches!(scheduler.tick(clock.now()).unwrap(), Tick::Repeat));
+    clock.advance(Duration::from_millis(500));
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(1));
+    assert_eq!(scheduler.dropped(), 0);
+}
+#[test]
+fn test_not_ready() {
+    let clock = SimulatedClock::new();
+    let mut scheduler = FrameScheduler::new(Live(frames(2), false));
+    assert!(matches!(scheduler.tick(clock.now()).unwrap(), Tick::Repeat));
+    scheduler.source_mut().1 = true;
+    assert_eq!(presented(scheduler.tick(clock.now()).unwrap()), Some(0));
+}
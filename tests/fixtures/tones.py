@@ -0,0 +1,257 @@
+#!/usr/bin/env python3
+"""Write the FLAC and Ogg Vorbis files the decoders are tested with.
+
+There is no encoder among kartina's dependencies, so the two files are put
+together here from the format specifications, as simply as each format allows:
+
+- triangle.flac: 8 kHz stereo, 16 bits, one second of a triangle wave of 8 Hz on the
+  left and its negative on the right, in blocks of 1024 samples.
+- tone.ogg: 8 kHz mono Vorbis, two seconds of a single frequency line around 281 Hz,
+  in short blocks of 256 samples, sixteen packets to a page.
+
+Run it from this directory to write the files again.
+"""
+
+import struct
+
+
+class MsbWriter:
+    """bits written most significant first, as FLAC reads them."""
+
+    def __init__(self):
+        self.bits = []
+
+    def write(self, value, count):
+        for i in reversed(range(count)):
+            self.bits.append((value >> i) & 1)
+
+    def align(self):
+        while len(self.bits) % 8:
+            self.bits.append(0)
+
+    def bytes(self):
+        self.align()
+        return bytes(
+            int("".join(map(str, self.bits[i : i + 8])), 2)
+            for i in range(0, len(self.bits), 8)
+        )
+
+
+class LsbWriter:
+    """bits written least significant first, as Vorbis reads them."""
+
+    def __init__(self):
+        self.bits = []
+
+    def write(self, value, count):
+        for i in range(count):
+            self.bits.append((value >> i) & 1)
+
+    def bytes(self):
+        while len(self.bits) % 8:
+            self.bits.append(0)
+        out = bytearray()
+        for i in range(0, len(self.bits), 8):
+            out.append(sum(bit << j for j, bit in enumerate(self.bits[i : i + 8])))
+        return bytes(out)
+
+
+def crc(data, poly, width):
+    """CRC of `data`, most significant bit first, starting from zero."""
+    top = 1 << (width - 1)
+    mask = (1 << width) - 1
+    value = 0
+    for byte in data:
+        value ^= byte << (width - 8)
+        for _ in range(8):
+            value = ((value << 1) ^ poly) if value & top else (value << 1)
+            value &= mask
+    return value
+
+
+# FLAC
+
+RATE = 8000
+FLAC_SAMPLES = 8000
+BLOCK = 1024
+
+
+def triangle(n):
+    n %= 1000
+    return 32 * min(n, 1000 - n)
+
+
+def rice(writer, residuals):
+    """residuals of one partition, with the Rice parameter that takes fewest bits."""
+    folded = [2 * r if r >= 0 else -2 * r - 1 for r in residuals]
+    k = min(range(15), key=lambda k: sum((u >> k) + 1 + k for u in folded))
+    writer.write(0, 2)  # 4-bit Rice parameters
+    writer.write(0, 4)  # a single partition
+    writer.write(k, 4)
+    for u in folded:
+        writer.write(0, u >> k)
+        writer.write(1, 1)
+        writer.write(u & ((1 << k) - 1), k)
+
+
+def subframe(writer, samples):
+    """a subframe predicted by the fixed second order predictor."""
+    writer.write(0, 1)
+    writer.write(0b001010, 6)
+    writer.write(0, 1)
+    for sample in samples[:2]:
+        writer.write(sample & 0xFFFF, 16)
+    rice(writer, [samples[i] - 2 * samples[i - 1] + samples[i - 2] for i in range(2, len(samples))])
+
+
+def flac():
+    out = bytearray(b"fLaC")
+    info = MsbWriter()
+    info.write(BLOCK, 16)
+    info.write(BLOCK, 16)
+    info.write(0, 24)
+    info.write(0, 24)
+    info.write(RATE, 20)
+    info.write(2 - 1, 3)
+    info.write(16 - 1, 5)
+    info.write(FLAC_SAMPLES, 36)
+    info.write(0, 128)
+    info = info.bytes()
+    out += bytes([0x80]) + len(info).to_bytes(3, "big") + info
+    for number, start in enumerate(range(0, FLAC_SAMPLES, BLOCK)):
+        size = min(BLOCK, FLAC_SAMPLES - start)
+        header = MsbWriter()
+        header.write(0xFFF8, 16)
+        header.write(0b1010 if size == BLOCK else 0b0111, 4)
+        header.write(0b0100, 4)  # 8 kHz
+        header.write(0b0001, 4)  # left and right
+        header.write(0b100, 3)  # 16 bits
+        header.write(0, 1)
+        header.write(number, 8)
+        if size != BLOCK:
+            header.write(size - 1, 16)
+        header = header.bytes()
+        frame = bytearray(header + bytes([crc(header, 0x07, 8)]))
+        body = MsbWriter()
+        left = [triangle(n) for n in range(start, start + size)]
+        subframe(body, left)
+        subframe(body, [-s for s in left])
+        frame += body.bytes()
+        frame += crc(frame, 0x8005, 16).to_bytes(2, "big")
+        out += frame
+    return bytes(out)
+
+
+# Ogg Vorbis
+
+PACKETS = 126
+PER_PAGE = 16
+
+
+def vorbis_float(value):
+    """`value`, a power of two or its negative, in the float format of Vorbis codebooks."""
+    sign = 0x80000000 if value < 0 else 0
+    exponent = abs(value).bit_length() - 1
+    return sign | ((exponent + 788 - 20) << 21) | (1 << 20)
+
+
+def codebook(writer, values=None):
+    """a codebook of two entries of one bit each, looking up `values` if there are any."""
+    writer.write(0x564342, 24)
+    writer.write(1, 16)  # dimensions
+    writer.write(2, 24)  # entries
+    writer.write(0, 1)  # not ordered
+    writer.write(0, 1)  # not sparse
+    writer.write(0, 5)
+    writer.write(0, 5)
+    if values is None:
+        writer.write(0, 4)
+        return
+    minimum, delta = values
+    writer.write(1, 4)
+    writer.write(vorbis_float(minimum), 32)
+    writer.write(vorbis_float(delta), 32)
+    writer.write(1 - 1, 4)  # one bit per multiplicand
+    writer.write(0, 1)
+    writer.write(0, 1)
+    writer.write(1, 1)
+
+
+def vorbis_headers():
+    ident = b"\x01vorbis" + struct.pack("<IBIiiiBB", 0, 1, RATE, 0, 0, 0, (11 << 4) | 8, 1)
+    vendor = b"kartina test tones"
+    comment = b"\x03vorbis" + struct.pack("<I", len(vendor)) + vendor + struct.pack("<IB", 0, 1)
+    setup = LsbWriter()
+    setup.write(2 - 1, 8)  # codebooks
+    codebook(setup)  # classifications
+    codebook(setup, (-1, 2))  # residue values of -1 and 1
+    setup.write(1 - 1, 6)  # time domain transforms
+    setup.write(0, 16)
+    setup.write(1 - 1, 6)  # floors
+    setup.write(1, 16)  # floor 1
+    setup.write(0, 5)  # without partitions, just a line
+    setup.write(1 - 1, 2)  # multiplier
+    setup.write(7, 4)  # range bits, for 128 lines
+    setup.write(1 - 1, 6)  # residues
+    setup.write(1, 16)  # residue 1
+    setup.write(9, 24)  # begin
+    setup.write(10, 24)  # end
+    setup.write(1 - 1, 24)  # partition size
+    setup.write(1 - 1, 6)  # classifications
+    setup.write(0, 8)  # classbook
+    setup.write(1, 3)  # first pass only
+    setup.write(0, 1)
+    setup.write(1, 8)  # value book
+    setup.write(1 - 1, 6)  # mappings
+    setup.write(0, 16)
+    setup.write(0, 1)  # one submap
+    setup.write(0, 1)  # no coupling
+    setup.write(0, 2)
+    setup.write(0, 8)
+    setup.write(0, 8)  # floor
+    setup.write(0, 8)  # residue
+    setup.write(1 - 1, 6)  # modes
+    setup.write(0, 1)  # short blocks
+    setup.write(0, 16)
+    setup.write(0, 16)
+    setup.write(0, 8)  # mapping
+    setup.write(1, 1)  # framing
+    return ident, comment, b"\x05vorbis" + setup.bytes()
+
+
+def audio_packet():
+    packet = LsbWriter()
+    packet.write(0, 1)  # audio
+    packet.write(1, 1)  # the floor is used
+    packet.write(180, 8)
+    packet.write(180, 8)
+    packet.write(0, 1)  # class 0
+    packet.write(1, 1)  # value 1
+    return packet.bytes()
+
+
+def page(packets, granule, sequence, flags):
+    lacing = bytes(len(packet) for packet in packets)
+    header = b"OggS" + struct.pack("<BBqIIIB", 0, flags, granule, 0x6B617274, sequence, 0, len(lacing))
+    data = bytearray(header + lacing + b"".join(packets))
+    data[22:26] = struct.pack("<I", crc(data, 0x04C11DB7, 32))
+    return bytes(data)
+
+
+def ogg():
+    ident, comment, setup = vorbis_headers()
+    out = page([ident], 0, 0, 0x02) + page([comment, setup], 0, 1, 0)
+    pages = range(0, PACKETS, PER_PAGE)
+    for sequence, first in enumerate(pages, 2):
+        last = min(first + PER_PAGE, PACKETS) - 1
+        flags = 0x04 if last == PACKETS - 1 else 0
+        # the first packet only starts the overlap, every other one finishes 128 samples
+        out += page([audio_packet()] * (last - first + 1), 128 * last, sequence, flags)
+    return out
+
+
+if __name__ == "__main__":
+    with open("triangle.flac", "wb") as f:
+        f.write(flac())
+    with open("tone.ogg", "wb") as f:
+        f.write(ogg())